@@ -1,66 +1,641 @@
 use std::io;
-use to_binary::BinaryString;
+use std::io::BufRead;
+use std::env;
+use std::fmt;
+use std::process;
+use std::time::Instant;
+use std::collections::HashMap;
+use num_bigint::BigInt;
 
 const DEBUG: bool = false;
 
 fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let mode = args.get(1).map(|s| s.as_str());
+    let ops = Registry::standard();
+    if mode == Some("compile") {
+        let source = args[2..].join(" ");
+        let expr = match Expr::compile(&source, &ops) {
+            Ok(expr) => expr,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            },
+        };
+        let packet = expr.to_packet();
+        let hex = match packet.to_hex() {
+            Ok(hex) => hex,
+            Err(e) => {
+                eprintln!("Could not encode transmission: {}", e);
+                process::exit(1);
+            },
+        };
+        let value = Packet::from_hex(&hex).unwrap().big_evaluate(&ops, 0).unwrap();
+        println!("{}", hex);
+        println!("Value: {}", value);
+        if value != expr.value(&ops) {
+            eprintln!("Evaluated {} but the expression gives {}", value, expr.value(&ops));
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if mode == Some("bench") {
+        bench();
+        return Ok(());
+    }
+    if mode == Some("--stream") {
+        // transmissions are padded to whole bytes, like those from to_hex
+        let align = match args.iter().position(|a| a == "--align") {
+            Some(i) => match args.get(i + 1).and_then(|n| n.parse::<usize>().ok()).filter(|&n| n > 0) {
+                Some(align) => align,
+                None => {
+                    eprintln!("--align expects a positive number of bits");
+                    process::exit(1);
+                },
+            },
+            None => 8,
+        };
+        if !stream(&ops, align)? {
+            process::exit(1);
+        }
+        return Ok(());
+    }
     let mut buf = String::new();
     io::stdin().read_line(&mut buf)?;
-    let mut raw_bits = BinaryString::from_hex(&buf.trim()).unwrap().0.chars().into_iter().map(|c| c != '0').collect::<Vec<bool>>();
-    let packet = Packet::parse(&mut raw_bits).unwrap();
-    println!("Sum of versions: {}", packet.add_versions());
-    println!("Value: {}", packet.evaluate());
+    let packet = match Packet::from_hex(buf.trim()) {
+        Ok(packet) => packet,
+        Err(e) => {
+            eprintln!("Could not decode transmission: {}", e);
+            process::exit(1);
+        },
+    };
+    // checked up front so every mode, --tree and --dot too, rejects packets
+    // the registry cannot evaluate
+    if let Err(e) = packet.check(&ops, 0) {
+        eprintln!("Invalid transmission: {}", e);
+        process::exit(1);
+    }
+    match mode {
+        Some("encode") => match packet.to_hex() {
+            Ok(hex) => println!("{}", hex),
+            Err(e) => {
+                eprintln!("Could not encode transmission: {}", e);
+                process::exit(1);
+            },
+        },
+        Some("--tree") => print!("{}", packet.tree(&ops)),
+        Some("--dot") => print!("{}", packet.to_dot(&ops)),
+        Some("--big") => {
+            println!("Sum of versions: {}", packet.add_versions());
+            match packet.big_evaluate(&ops, 0) {
+                Ok(value) => println!("Value: {}", value),
+                Err(e) => {
+                    eprintln!("Could not evaluate transmission: {}", e);
+                    process::exit(1);
+                },
+            }
+        },
+        _ => {
+            println!("Sum of versions: {}", packet.add_versions());
+            match packet.evaluate(&ops) {
+                Ok(value) => println!("Value: {}", value),
+                Err(e @ EvalError::Overflow(..)) => {
+                    eprintln!("Could not evaluate transmission: {} (try --big)", e);
+                    process::exit(1);
+                },
+                Err(e) => {
+                    eprintln!("Could not evaluate transmission: {}", e);
+                    process::exit(1);
+                },
+            }
+        },
+    }
     Ok(())
 }
 
+// An odd number of hex digits leaves the last byte half filled; the cursor is
+// told the real length in bits.
+fn decode_hex(s: &str) -> Result<Vec<u8>, DecodeError> {
+    let mut bytes = vec![0; s.len().div_ceil(2)];
+    for (i, c) in s.chars().enumerate() {
+        let d = c.to_digit(16).ok_or_else(|| DecodeError::InvalidHex(Location::at(4*i), c))?;
+        bytes[i / 2] |= (d as u8) << (4 * (1 - i % 2));
+    }
+    Ok(bytes)
+}
+
+// Decodes every line of stdin, printing each packet as soon as it is read.
+// Returns false if any line failed to decode.
+fn stream(ops: &Registry, align: usize) -> io::Result<bool> {
+    let mut ok = true;
+    for (n, line) in io::stdin().lock().lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut count = 0;
+        let r = decode_stream(line.trim(), align, |packet| {
+            count += 1;
+            match packet.big_evaluate(ops, 0) {
+                Ok(value) => println!("line {} packet {}: sum of versions {}, value {}",
+                    n + 1, count, packet.add_versions(), value),
+                Err(e) => {
+                    eprintln!("line {} packet {}: could not evaluate: {}", n + 1, count, e);
+                    ok = false;
+                },
+            }
+        });
+        if let Err(e) = r {
+            eprintln!("line {} packet {}: could not decode: {}", n + 1, count + 1, e);
+            ok = false;
+        }
+    }
+    Ok(ok)
+}
+
+// A line may hold several transmissions one after another, each padded with
+// zeros to a multiple of `align` bits: 8 for whole bytes, 4 for hex digits or
+// 1 for packets back to back. Padding, like anything after the last one,
+// must be zero bits.
+fn decode_stream(hex: &str, align: usize, mut emit: impl FnMut(Packet)) -> Result<(), DecodeError> {
+    let bytes = decode_hex(hex)?;
+    let mut cursor = BitCursor::new(&bytes, 4 * hex.len());
+    while cursor.next_set_bit().is_some() {
+        emit(Packet::parse(&mut cursor)?);
+        let padding = (align - cursor.pos % align) % align;
+        let span = cursor.take(padding.min(cursor.remaining())).unwrap();
+        if let Some(pos) = span.next_set_bit() {
+            return Err(DecodeError::TrailingPadding(Location::at(pos)));
+        }
+    }
+    Ok(())
+}
+
+// Times decoding of generated transmissions with BitCursor against the
+// original clone-and-reverse Vec<bool> parser.
+fn bench() {
+    println!("{:>10} {:>12} {:>12} {:>12}", "literals", "bits", "cursor", "vec");
+    for literals in [1_000, 2_000, 4_000, 8_000, 100_000, 1_000_000] {
+        let packet = bench_packet(literals);
+        let hex = packet.to_hex().unwrap();
+        let start = Instant::now();
+        let decoded = Packet::from_hex(&hex).unwrap();
+        let cursor = start.elapsed();
+        assert_eq!(decoded, packet);
+        // the old parser is quadratic, so only try it on the small inputs
+        let vec = if literals <= 8_000 {
+            let start = Instant::now();
+            let decoded = legacy::parse_packet(&mut legacy::hex_to_bits(&hex)).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(decoded, packet);
+            format!("{:.2?}", elapsed)
+        } else {
+            String::from("-")
+        };
+        println!("{:>10} {:>12} {:>12} {:>12}", literals, 4 * hex.len(), format!("{:.2?}", cursor), vec);
+    }
+}
+
+// A sum of sums, each inner sum holding up to 1000 literals.
+fn bench_packet(literals: usize) -> Packet {
+    let values: Vec<i64> = (0..literals as i64).collect();
+    let children = values.chunks(1000)
+        .map(|chunk| Packet::operator(1, 0, chunk.iter().map(|&n| Packet::literal(2, n)).collect()))
+        .collect();
+    Packet::operator(0, 0, children)
+}
+
+#[derive(Debug, PartialEq)]
+enum EvalError {
+    // name of the packet type whose value does not fit
+    Overflow(Location, String),
+    UnknownType(Location, i32),
+    // operation name, number of sub-packets, what the operation accepts
+    WrongArity(Location, String, usize, Arity),
+}
+
+impl EvalError {
+    fn location(&mut self) -> &mut Location {
+        match self {
+            EvalError::Overflow(l, _)
+            | EvalError::UnknownType(l, _)
+            | EvalError::WrongArity(l, _, _, _) => l,
+        }
+    }
+
+    fn nested(mut self, child: usize) -> Self {
+        self.location().path.insert(0, child);
+        self
+    }
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Overflow(l, name) => write!(f, "{}: {} overflows i64", l, name),
+            EvalError::UnknownType(l, type_id) => write!(f, "{}: no operation registered for type ID {}", l, type_id),
+            EvalError::WrongArity(l, name, n, arity) => {
+                write!(f, "{}: {} has {} sub-packets but needs {}", l, name, n, arity)
+            },
+        }
+    }
+}
+
+// How many sub-packets an operation accepts.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    fn allows(&self, n: usize) -> bool {
+        match *self {
+            Arity::Exactly(m) => n == m,
+            Arity::AtLeast(m) => n >= m,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arity::Exactly(n) => write!(f, "exactly {}", n),
+            Arity::AtLeast(n) => write!(f, "at least {}", n),
+        }
+    }
+}
+
+// What an operator packet does with the values of its sub-packets.
+trait Operation {
+    fn name(&self) -> &str;
+    fn arity(&self) -> Arity;
+    // None if the result does not fit in an i64.
+    fn apply(&self, values: &[i64]) -> Option<i64>;
+    fn apply_big(&self, values: &[BigInt]) -> BigInt;
+}
+
+// The puzzle's own operations, as plain functions.
+struct Builtin {
+    name: &'static str,
+    arity: Arity,
+    apply: fn(&[i64]) -> Option<i64>,
+    apply_big: fn(&[BigInt]) -> BigInt,
+}
+
+impl Operation for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    fn apply(&self, values: &[i64]) -> Option<i64> {
+        (self.apply)(values)
+    }
+
+    fn apply_big(&self, values: &[BigInt]) -> BigInt {
+        (self.apply_big)(values)
+    }
+}
+
+// Operations by type ID. Type IDs are only 3 bits and 4 always means a
+// literal, so an extra operation has to take over one of the other seven.
+struct Registry {
+    operations: HashMap<i32, Box<dyn Operation>>,
+}
+
+impl Registry {
+    fn new() -> Self {
+        Self{
+            operations: HashMap::new(),
+        }
+    }
+
+    fn standard() -> Self {
+        let mut r = Registry::new();
+        r.register(0, Builtin{
+            name: "sum",
+            arity: Arity::AtLeast(1),
+            apply: |v| v.iter().try_fold(0i64, |acc, &x| acc.checked_add(x)),
+            apply_big: |v| v.iter().sum(),
+        });
+        r.register(1, Builtin{
+            name: "product",
+            arity: Arity::AtLeast(1),
+            apply: |v| v.iter().try_fold(1i64, |acc, &x| acc.checked_mul(x)),
+            apply_big: |v| v.iter().product(),
+        });
+        r.register(2, Builtin{
+            name: "min",
+            arity: Arity::AtLeast(1),
+            apply: |v| v.iter().min().copied(),
+            apply_big: |v| v.iter().min().unwrap().clone(),
+        });
+        r.register(3, Builtin{
+            name: "max",
+            arity: Arity::AtLeast(1),
+            apply: |v| v.iter().max().copied(),
+            apply_big: |v| v.iter().max().unwrap().clone(),
+        });
+        r.register(5, Builtin{
+            name: "gt",
+            arity: Arity::Exactly(2),
+            apply: |v| Some((v[0] > v[1]) as i64),
+            apply_big: |v| BigInt::from((v[0] > v[1]) as u8),
+        });
+        r.register(6, Builtin{
+            name: "lt",
+            arity: Arity::Exactly(2),
+            apply: |v| Some((v[0] < v[1]) as i64),
+            apply_big: |v| BigInt::from((v[0] < v[1]) as u8),
+        });
+        r.register(7, Builtin{
+            name: "eq",
+            arity: Arity::Exactly(2),
+            apply: |v| Some((v[0] == v[1]) as i64),
+            apply_big: |v| BigInt::from((v[0] == v[1]) as u8),
+        });
+        r
+    }
+
+    // Replaces whatever was registered for `type_id` before.
+    fn register(&mut self, type_id: i32, operation: impl Operation + 'static) {
+        assert!((0..8).contains(&type_id) && type_id != 4, "type ID {} cannot be an operator", type_id);
+        self.operations.insert(type_id, Box::new(operation));
+    }
+
+    fn get(&self, type_id: i32) -> Option<&dyn Operation> {
+        self.operations.get(&type_id).map(|op| op.as_ref())
+    }
+
+    fn find(&self, name: &str) -> Option<i32> {
+        self.operations.iter().find(|(_, op)| op.name() == name).map(|(&type_id, _)| type_id)
+    }
+
+    fn name(&self, type_id: i32) -> &str {
+        match self.get(type_id) {
+            _ if type_id == 4 => "literal",
+            Some(op) => op.name(),
+            None => "unknown",
+        }
+    }
+}
+
+// Where a decode failed: the bit offset from the start of the transmission and
+// the child indices leading from the outermost packet to the failing one.
+#[derive(Debug, PartialEq)]
+struct Location {
+    offset: usize,
+    path: Vec<usize>,
+}
+
+impl Location {
+    fn at(offset: usize) -> Self {
+        Self{
+            offset,
+            path: vec![],
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bit {} (root", self.offset)?;
+        for child in &self.path {
+            write!(f, " → child {}", child)?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum DecodeError {
+    InvalidHex(Location, char),
+    TruncatedHeader(Location),
+    TruncatedLiteral(Location),
+    TruncatedLength(Location),
+    // declared length in bits, bits actually remaining
+    BadLength(Location, usize, usize),
+    TrailingPadding(Location),
+}
+
+impl DecodeError {
+    fn location(&mut self) -> &mut Location {
+        match self {
+            DecodeError::InvalidHex(l, _)
+            | DecodeError::TruncatedHeader(l)
+            | DecodeError::TruncatedLiteral(l)
+            | DecodeError::TruncatedLength(l)
+            | DecodeError::BadLength(l, _, _)
+            | DecodeError::TrailingPadding(l) => l,
+        }
+    }
+
+    // Offsets are already absolute; parents only add themselves to the path.
+    fn nested(mut self, child: usize) -> Self {
+        self.location().path.insert(0, child);
+        self
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum EncodeError {
+    // bit length of the children
+    TooLong(Location, usize),
+    // number of children
+    TooMany(Location, usize),
+}
+
+impl EncodeError {
+    fn nested(mut self, child: usize) -> Self {
+        match &mut self {
+            EncodeError::TooLong(l, _) | EncodeError::TooMany(l, _) => l.path.insert(0, child),
+        }
+        self
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::TooLong(l, bits) => {
+                write!(f, "{}: children take {} bits, more than length type 0 allows ({})", l, bits, (1 << 15) - 1)
+            },
+            EncodeError::TooMany(l, n) => {
+                write!(f, "{}: {} children, more than length type 1 allows ({})", l, n, (1 << 11) - 1)
+            },
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::InvalidHex(l, c) => write!(f, "{}: invalid hex digit '{}'", l, c),
+            DecodeError::TruncatedHeader(l) => write!(f, "{}: truncated packet header", l),
+            DecodeError::TruncatedLiteral(l) => write!(f, "{}: truncated literal value", l),
+            DecodeError::TruncatedLength(l) => write!(f, "{}: truncated length field", l),
+            DecodeError::BadLength(l, length, available) => {
+                write!(f, "{}: length field gives {} bits but only {} remain", l, length, available)
+            },
+            DecodeError::TrailingPadding(l) => write!(f, "{}: non-zero bit in trailing padding", l),
+        }
+    }
+}
+
+// Appends the lowest `width` bits of `value`, most significant first.
+fn push_bits(bits: &mut Vec<bool>, value: u64, width: usize) {
+    for i in (0..width).rev() {
+        bits.push((value >> i) & 1 == 1);
+    }
+}
+
+// Reads bits most significant first out of a borrowed byte slice. Positions
+// are absolute offsets into the whole transmission.
+#[derive(Clone, Copy)]
+struct BitCursor<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> BitCursor<'a> {
+    fn new(bytes: &'a [u8], len: usize) -> Self {
+        assert!(len <= 8 * bytes.len());
+        Self{
+            bytes,
+            pos: 0,
+            end: len,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn bit(&mut self) -> Option<bool> {
+        if self.pos == self.end {
+            return None;
+        }
+        let b = (self.bytes[self.pos / 8] >> (7 - self.pos % 8)) & 1 == 1;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn read(&mut self, width: usize) -> Option<u64> {
+        if self.remaining() < width {
+            return None;
+        }
+        let mut r = 0;
+        for _ in 0..width {
+            r = 2*r + self.bit().unwrap() as u64;
+        }
+        Some(r)
+    }
+
+    // Position of the next 1 bit, without moving the cursor.
+    fn next_set_bit(&self) -> Option<usize> {
+        let mut cursor = *self;
+        while let Some(b) = cursor.bit() {
+            if b {
+                return Some(cursor.pos - 1);
+            }
+        }
+        None
+    }
+
+    // Splits off the next `len` bits as a cursor of their own.
+    fn take(&mut self, len: usize) -> Option<Self> {
+        if self.remaining() < len {
+            return None;
+        }
+        let r = Self{
+            bytes: self.bytes,
+            pos: self.pos,
+            end: self.pos + len,
+        };
+        self.pos += len;
+        Some(r)
+    }
+}
+
+#[derive(Debug, PartialEq)]
 struct Packet {
     version: i32,
     type_id: i32,
     child: Type,
 }
 
+#[derive(Debug, PartialEq)]
 struct Literal {
     raw: Vec<bool>,
 }
 
 impl Literal {
-    fn value(self: &Self) -> i64 {
-        let mut r = 0;
+    // None if the literal is too wide for an i64.
+    fn value(&self) -> Option<i64> {
+        let mut r: i64 = 0;
+        for &bit in &self.raw {
+            r = r.checked_mul(2)?.checked_add(bit as i64)?;
+        }
+        Some(r)
+    }
+
+    // Literals can be any number of groups long.
+    fn big_value(&self) -> BigInt {
+        let mut r = BigInt::default();
         for &bit in &self.raw {
-            r = 2*r + (bit as i64);
+            r = r * 2 + (bit as u8);
         }
         r
     }
 
-    fn parse(bits: &mut Vec<bool>) -> Option<Literal> {
+    fn parse(cursor: &mut BitCursor) -> Result<Literal, DecodeError> {
         if DEBUG{
-            println!("Literal::parse: {:?}", bits);
+            println!("Literal::parse at bit {}", cursor.pos);
         }
-        let mut newbits = bits.clone();
-        newbits.reverse();
         let mut v = vec![];
         loop {
-            let end = match newbits.pop() {
-                Some(x) => { !x },
-                None => {
-                    return None;
-                },
-            };
+            if cursor.remaining() < 5 {
+                return Err(DecodeError::TruncatedLiteral(Location::at(cursor.pos)));
+            }
+            let end = !cursor.bit().unwrap();
             for _ in 0..4 {
-                match newbits.pop() {
-                    Some(bit) => { v.push(bit); },
-                    None => {
-                        return None;
-                    },
-                }
+                v.push(cursor.bit().unwrap());
             }
             if end {
                 break;
             }
         }
-        newbits.reverse();
-        *bits = newbits;
-        Some(Literal::from(v))
+        Ok(Literal::from(v))
+    }
+
+    // Groups of four bits, each prefixed by 1 except the last; the raw value
+    // is left-padded with zeroes to a whole number of groups.
+    fn encode(&self, bits: &mut Vec<bool>) {
+        let mut raw = vec![false; (4 - self.raw.len() % 4) % 4];
+        raw.extend(&self.raw);
+        if raw.is_empty() {
+            raw = vec![false; 4];
+        }
+        let groups = raw.len() / 4;
+        for (i, group) in raw.chunks(4).enumerate() {
+            bits.push(i + 1 < groups);
+            bits.extend(group);
+        }
+    }
+}
+
+impl From<i64> for Literal {
+    fn from(value: i64) -> Self {
+        assert!(value >= 0, "literals cannot be negative: {}", value);
+        let width = (64 - value.leading_zeros() as usize).max(1);
+        let mut raw = vec![];
+        push_bits(&mut raw, value as u64, width.div_ceil(4) * 4);
+        Self { raw }
     }
 }
 
@@ -72,171 +647,755 @@ impl From<Vec<bool>> for Literal {
 }
 
 impl Packet {
-    fn parse(raw_bits: &mut Vec<bool>) -> Option<Self> {
+    fn literal(version: i32, value: i64) -> Self {
+        Self{
+            version,
+            type_id: 4,
+            child: Type::Literal(Literal::from(value)),
+        }
+    }
+
+    fn operator(version: i32, type_id: i32, children: Vec<Packet>) -> Self {
+        Self{
+            version,
+            type_id,
+            child: Type::Operator(Operator::new(children)),
+        }
+    }
+
+    // Decodes a whole transmission: one packet followed only by zero padding.
+    fn from_hex(hex: &str) -> Result<Self, DecodeError> {
+        let bytes = decode_hex(hex)?;
+        let mut cursor = BitCursor::new(&bytes, 4 * hex.len());
+        let packet = Packet::parse(&mut cursor)?;
+        if let Some(pos) = cursor.next_set_bit() {
+            return Err(DecodeError::TrailingPadding(Location::at(pos)));
+        }
+        Ok(packet)
+    }
+
+    fn parse(cursor: &mut BitCursor) -> Result<Self, DecodeError> {
         if DEBUG {
-            println!("Packet::parse {:?}", raw_bits);
+            println!("Packet::parse at bit {}", cursor.pos);
         }
+        if cursor.remaining() < 6 {
+            return Err(DecodeError::TruncatedHeader(Location::at(cursor.pos)));
+        }
+        let version = cursor.read(3).unwrap() as i32;
+        let type_id = cursor.read(3).unwrap() as i32;
+        //delegate to literal or operator parse
+        let child = if type_id == 4 {
+            Type::Literal(Literal::parse(cursor)?)
+        } else {
+            Type::Operator(Operator::parse(cursor)?)
+        };
+        Ok(Self{
+            version,
+            type_id,
+            child,
+        })
+    }
+
+    // Number of bits this packet occupies in its transmission.
+    fn bit_len(&self) -> usize {
+        match &self.child {
+            Type::Literal(lit) => 6 + lit.raw.len().max(4).div_ceil(4) * 5,
+            Type::Operator(op) => {
+                op.header_len() + op.children.iter().map(|c| c.bit_len()).sum::<usize>()
+            },
+        }
+    }
+
+    fn add_versions(&self) -> i32 {
+        let mut r: i32 = 0;
+        r += self.version;
+        match &self.child {
+            Type::Literal(_) => {},
+            Type::Operator(op) => {
+                r += op.add_versions();
+            },
+        }
+        r
+    }
+
+    // Fails if some operator's children don't fit its length field.
+    fn encode(&self) -> Result<Vec<bool>, EncodeError> {
+        self.check_lengths(0)?;
+        let mut bits = vec![];
+        self.encode_into(&mut bits);
+        Ok(bits)
+    }
+
+    fn check_lengths(&self, start: usize) -> Result<(), EncodeError> {
+        if let Type::Operator(op) = &self.child {
+            let body = self.bit_len() - op.header_len();
+            match op.length_type {
+                LengthType::Bits if body >= 1 << 15 => {
+                    return Err(EncodeError::TooLong(Location::at(start), body));
+                },
+                LengthType::Count if op.children.len() >= 1 << 11 => {
+                    return Err(EncodeError::TooMany(Location::at(start), op.children.len()));
+                },
+                _ => {},
+            }
+            for (i, (offset, c)) in op.children_at(start).enumerate() {
+                c.check_lengths(offset).map_err(|e| e.nested(i))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_into(&self, bits: &mut Vec<bool>) {
+        push_bits(bits, self.version as u64, 3);
+        push_bits(bits, self.type_id as u64, 3);
+        match &self.child {
+            Type::Literal(lit) => lit.encode(bits),
+            Type::Operator(op) => op.encode(bits),
+        }
+    }
+
+    // Hex transmission, zero-padded to a whole number of bytes.
+    fn to_hex(&self) -> Result<String, EncodeError> {
+        let mut bits = self.encode()?;
+        while !bits.len().is_multiple_of(8) {
+            bits.push(false);
+        }
+        let bytes: Vec<u8> = bits.chunks(8)
+            .map(|byte| byte.iter().fold(0, |acc, &b| 2*acc + b as u8))
+            .collect();
+        Ok(hex::encode_upper(bytes))
+    }
+
+    fn evaluate(&self, ops: &Registry) -> Result<i64, EvalError> {
+        self.checked_evaluate(ops, 0)
+    }
+
+    // The operation registered for this packet's type, if it accepts `n`
+    // sub-packets. `start` is the offset of this packet within the transmission.
+    fn operation<'r>(&self, ops: &'r Registry, start: usize, n: usize) -> Result<&'r dyn Operation, EvalError> {
+        let operation = match ops.get(self.type_id) {
+            Some(operation) => operation,
+            None => { return Err(EvalError::UnknownType(Location::at(start), self.type_id)); }
+        };
+        if !operation.arity().allows(n) {
+            return Err(EvalError::WrongArity(Location::at(start), operation.name().to_string(), n, operation.arity()));
+        }
+        Ok(operation)
+    }
+
+    // Every operator's type is registered and has an allowed number of
+    // sub-packets. Gives the packet's end offset.
+    fn check(&self, ops: &Registry, start: usize) -> Result<usize, EvalError> {
+        match &self.child {
+            Type::Literal(_) => Ok(start + self.bit_len()),
+            Type::Operator(op) => {
+                self.operation(ops, start, op.children.len())?;
+                let mut end = start + op.header_len();
+                for (i, c) in op.children.iter().enumerate() {
+                    end = c.check(ops, end).map_err(|e| e.nested(i))?;
+                }
+                Ok(end)
+            },
+        }
+    }
+
+    // Reports the packet whose value first overflows i64.
+    fn checked_evaluate(&self, ops: &Registry, start: usize) -> Result<i64, EvalError> {
+        match &self.child {
+            Type::Literal(lit) => {
+                lit.value().ok_or_else(|| EvalError::Overflow(Location::at(start), String::from("literal")))
+            },
+            Type::Operator(op) => {
+                let operation = self.operation(ops, start, op.children.len())?;
+                let mut values = vec![];
+                for (i, (offset, c)) in op.children_at(start).enumerate() {
+                    values.push(c.checked_evaluate(ops, offset).map_err(|e| e.nested(i))?);
+                }
+                operation.apply(&values)
+                    .ok_or_else(|| EvalError::Overflow(Location::at(start), operation.name().to_string()))
+            },
+        }
+    }
+
+    fn big_evaluate(&self, ops: &Registry, start: usize) -> Result<BigInt, EvalError> {
+        match &self.child {
+            Type::Literal(lit) => Ok(lit.big_value()),
+            Type::Operator(op) => {
+                let operation = self.operation(ops, start, op.children.len())?;
+                let mut values = vec![];
+                for (i, (offset, c)) in op.children_at(start).enumerate() {
+                    values.push(c.big_evaluate(ops, offset).map_err(|e| e.nested(i))?);
+                }
+                Ok(operation.apply_big(&values))
+            },
+        }
+    }
+
+    // Each packet's span and value in pre-order, worked out in one pass: every
+    // child hands its end and value up to its parent.
+    fn annotate(&self, ops: &Registry, start: usize, notes: &mut Vec<Note>) -> usize {
+        let slot = notes.len();
+        notes.push(Note{
+            start,
+            end: start,
+            value: None,
+        });
+        let (end, value) = match &self.child {
+            Type::Literal(lit) => (start + self.bit_len(), Some(lit.big_value())),
+            Type::Operator(op) => {
+                let mut end = start + op.header_len();
+                let mut values = Some(vec![]);
+                for c in &op.children {
+                    let i = c.annotate(ops, end, notes);
+                    end = notes[i].end;
+                    values = values.zip(notes[i].value.clone()).map(|(mut v, x)| { v.push(x); v });
+                }
+                let operation = self.operation(ops, start, op.children.len()).ok();
+                (end, operation.zip(values).map(|(o, v)| o.apply_big(&v)))
+            },
+        };
+        notes[slot].end = end;
+        notes[slot].value = value;
+        slot
+    }
+
+    // One line per packet, children indented beneath their operator, with
+    // each packet's bit span in the transmission.
+    fn fmt_tree(&self, f: &mut fmt::Formatter, ops: &Registry, notes: &[Note], next: &mut usize, depth: usize) -> fmt::Result {
+        let note = &notes[*next];
+        *next += 1;
+        write!(f, "{:indent$}v{} {}", "", self.version, ops.name(self.type_id), indent = 2*depth)?;
+        match &self.child {
+            Type::Literal(lit) => writeln!(f, " {} [bits {}..{}]", lit.big_value(), note.start, note.end),
+            Type::Operator(op) => {
+                let length = match op.length_type {
+                    LengthType::Bits => format!("length type 0: {} bits", note.end - note.start - op.header_len()),
+                    LengthType::Count => format!("length type 1: {} sub-packets", op.children.len()),
+                };
+                writeln!(f, " = {} ({}) [bits {}..{}]", note.value_str(), length, note.start, note.end)?;
+                for c in &op.children {
+                    c.fmt_tree(f, ops, notes, next, depth + 1)?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn tree<'a>(&'a self, ops: &'a Registry) -> Tree<'a> {
+        Tree{
+            packet: self,
+            ops,
+        }
+    }
+
+    // Graphviz digraph of the packet tree, each operator labelled with the
+    // value it evaluates to.
+    fn to_dot(&self, ops: &Registry) -> String {
+        let mut notes = vec![];
+        self.annotate(ops, 0, &mut notes);
+        let mut r = String::from("digraph packet {\n");
+        self.dot_nodes(ops, &notes, &mut r, &mut 0);
+        r.push_str("}\n");
+        r
+    }
+
+    // Node ids are pre-order indices, so they double as indices into `notes`.
+    fn dot_nodes(&self, ops: &Registry, notes: &[Note], out: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+        match &self.child {
+            Type::Literal(lit) => {
+                out.push_str(&format!("    p{} [shape=ellipse, label=\"v{} {}\"];\n", id, self.version, lit.big_value()));
+            },
+            Type::Operator(op) => {
+                out.push_str(&format!("    p{} [shape=box, label=\"v{} {}\\n= {}\"];\n",
+                    id, self.version, ops.name(self.type_id), notes[id].value_str()));
+                for c in &op.children {
+                    let child_id = c.dot_nodes(ops, notes, out, next_id);
+                    out.push_str(&format!("    p{} -> p{};\n", id, child_id));
+                }
+            },
+        }
+        id
+    }
+}
+
+// Where a packet sits in the transmission and what it evaluates to, if it
+// does.
+struct Note {
+    start: usize,
+    end: usize,
+    value: Option<BigInt>,
+}
+
+impl Note {
+    fn value_str(&self) -> String {
+        self.value.as_ref().map_or(String::from("?"), |v| v.to_string())
+    }
+}
+
+// The standard operations' tree; see Packet::tree for others.
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.tree(&Registry::standard()).fmt(f)
+    }
+}
+
+// A packet tree displayed with the names and values of a given registry.
+struct Tree<'a> {
+    packet: &'a Packet,
+    ops: &'a Registry,
+}
+
+impl fmt::Display for Tree<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut notes = vec![];
+        self.packet.annotate(self.ops, 0, &mut notes);
+        self.packet.fmt_tree(f, self.ops, &notes, &mut 0, 0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Type {
+    Literal(Literal),
+    Operator(Operator),
+}
+
+// Length type ID 0 gives the total bit length of the children in 15 bits;
+// length type ID 1 gives the number of children in 11 bits.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum LengthType {
+    Bits,
+    Count,
+}
+
+#[derive(Debug, PartialEq)]
+struct Operator {
+    length_type: LengthType,
+    children: Vec<Packet>,
+}
+
+impl Operator {
+    // Prefers the shorter 11-bit child count, falling back to the bit length
+    // when there are too many children to count. Even that can be too short,
+    // which encoding reports.
+    fn new(children: Vec<Packet>) -> Self {
+        let length_type = if children.len() < 1 << 11 {
+            LengthType::Count
+        } else {
+            LengthType::Bits
+        };
+        Self{
+            length_type,
+            children,
+        }
+    }
+
+    fn parse(cursor: &mut BitCursor) -> Result<Self, DecodeError> {
+        if DEBUG {
+            println!("Operator::parse at bit {}", cursor.pos);
+        }
+        let start = cursor.pos;
+        let length_type_id = match cursor.bit() {
+            Some(b) => b,
+            None => { return Err(DecodeError::TruncatedLength(Location::at(start))); }
+        };
+        let mut children = vec![];
+        if length_type_id {
+            let n = match cursor.read(11) {
+                Some(n) => n as usize,
+                None => { return Err(DecodeError::TruncatedLength(Location::at(start + 1))); }
+            };
+            for i in 0..n {
+                children.push(Packet::parse(cursor).map_err(|e| e.nested(i))?);
+            }
+            Ok(Self{
+                length_type: LengthType::Count,
+                children,
+            })
+        } else {
+            let n = match cursor.read(15) {
+                Some(n) => n as usize,
+                None => { return Err(DecodeError::TruncatedLength(Location::at(start + 1))); }
+            };
+            let mut span = match cursor.take(n) {
+                Some(span) => span,
+                None => {
+                    return Err(DecodeError::BadLength(Location::at(start + 1), n, cursor.remaining()));
+                },
+            };
+            while span.remaining() > 0 {
+                let i = children.len();
+                children.push(Packet::parse(&mut span).map_err(|e| e.nested(i))?);
+            }
+            Ok(Self{
+                length_type: LengthType::Bits,
+                children,
+            })
+        }
+    }
+
+    // The packet header plus the length type and length fields.
+    fn header_len(&self) -> usize {
+        match self.length_type {
+            LengthType::Bits => 22,
+            LengthType::Count => 18,
+        }
+    }
+
+    // Each child with its offset, given the offset of this operator packet.
+    fn children_at(&self, start: usize) -> impl Iterator<Item = (usize, &Packet)> {
+        self.children.iter().scan(start + self.header_len(), |offset, c| {
+            let r = (*offset, c);
+            *offset += c.bit_len();
+            Some(r)
+        })
+    }
+
+    fn encode(&self, bits: &mut Vec<bool>) {
+        let mut body = vec![];
+        for c in &self.children {
+            c.encode_into(&mut body);
+        }
+        match self.length_type {
+            LengthType::Bits => {
+                assert!(body.len() < 1 << 15, "children too long for length type 0: {} bits", body.len());
+                bits.push(false);
+                push_bits(bits, body.len() as u64, 15);
+            },
+            LengthType::Count => {
+                assert!(self.children.len() < 1 << 11, "too many children for length type 1: {}", self.children.len());
+                bits.push(true);
+                push_bits(bits, self.children.len() as u64, 11);
+            },
+        }
+        bits.extend(body);
+    }
+
+    fn add_versions(&self) -> i32 {
+        let mut r = 0;
+        for c in &self.children {
+            r += c.add_versions();
+        }
+        r
+    }
+}
+
+// The original decoder, which clones and reverses the remaining bits at every
+// step. Kept only so `bench` has something to compare BitCursor against.
+mod legacy {
+    use super::{push_bits, Literal, Operator, Packet, Type, LengthType};
+
+    pub fn hex_to_bits(s: &str) -> Vec<bool> {
+        let mut bits = vec![];
+        for c in s.chars() {
+            push_bits(&mut bits, c.to_digit(16).unwrap() as u64, 4);
+        }
+        bits
+    }
+
+    fn parse_literal(bits: &mut Vec<bool>) -> Option<Literal> {
+        let mut newbits = bits.clone();
+        newbits.reverse();
+        let mut v = vec![];
+        loop {
+            let end = !newbits.pop()?;
+            for _ in 0..4 {
+                v.push(newbits.pop()?);
+            }
+            if end {
+                break;
+            }
+        }
+        newbits.reverse();
+        *bits = newbits;
+        Some(Literal::from(v))
+    }
+
+    pub fn parse_packet(raw_bits: &mut Vec<bool>) -> Option<Packet> {
         let mut newbits = raw_bits.clone();
         newbits.reverse();
-        //get version
         let mut version = 0;
         for _ in 0..3 {
-            if let Some(b) = newbits.pop() {
-                version = 2*version + (b as i32);
-            } else {
-                return None;
-            }
+            version = 2*version + (newbits.pop()? as i32);
         }
-        //get type id
         let mut type_id = 0;
         for _ in 0..3 {
-            if let Some(b) = newbits.pop() {
-                type_id = 2*type_id + (b as i32);
-            } else {
-                return None;
-            }
+            type_id = 2*type_id + (newbits.pop()? as i32);
         }
-        //delegate to literal or operator parse
         newbits.reverse();
         let child = if type_id == 4 {
-            Type::Literal(Literal::parse(&mut newbits).unwrap())
+            Type::Literal(parse_literal(&mut newbits)?)
         } else {
-            Type::Operator(Operator::parse(&mut newbits).unwrap())
+            Type::Operator(parse_operator(&mut newbits)?)
         };
         *raw_bits = newbits;
-        Some(Self{
+        Some(Packet{
             version,
             type_id,
             child,
         })
     }
 
-    fn add_versions(self: &Self) -> i32 {
-        let mut r: i32 = 0;
-        r += self.version;
-        match &self.child {
-            Type::Literal(_) => {},
-            Type::Operator(op) => {
-                r += op.add_versions();
-            },
-        }
-        r
-    }
-
-    fn evaluate(self: &Self) -> i64 {
-        match &self.child {
-            Type::Literal(lit) => {
-                lit.value()
-            },
-            Type::Operator(op) => {
-                let mut iter = op.children.iter().map(|p| p.evaluate());
-                match self.type_id {
-                    0 => {
-                        iter.fold(0, |acc, x| acc + x)
-                    },
-                    1 => {
-                        iter.fold(1, |acc, x| acc * x)
-                    },
-                    2 => {
-                        iter.min().unwrap()
-                    },
-                    3 => {
-                        iter.max().unwrap()
-                    },
-                    5 => {
-                        let (a, b) = (iter.next().unwrap(), iter.next().unwrap());
-                        (a > b) as i64
-                    },
-                    6 => {
-                        let (a, b) = (iter.next().unwrap(), iter.next().unwrap());
-                        (a < b) as i64
-                    },
-                    7 => {
-                        let (a, b) = (iter.next().unwrap(), iter.next().unwrap());
-                        (a == b) as i64
-                    },
-                    _ => {
-                        0
-                    }
-                }
-            }
-        }
-    }
-}
-
-enum Type {
-    Literal(Literal),
-    Operator(Operator),
-}
-
-struct Operator {
-    children: Vec<Packet>,
-}
-
-impl Operator {
-    fn parse(raw_bits: &mut Vec<bool>) -> Option<Self> {
-        if DEBUG {
-            println!("Operator::parse {:?}", raw_bits);
-        }
+    fn parse_operator(raw_bits: &mut Vec<bool>) -> Option<Operator> {
         let mut newbits = raw_bits.clone();
         newbits.reverse();
         let mut v = vec![];
-        let length_type_id = match newbits.pop() {
-            Some(b) => b,
-            None => { return None; }
-        };
+        let length_type_id = newbits.pop()?;
+        let mut children = vec![];
         if length_type_id {
             let mut n = 0;
             for _ in 0..11 {
-                if let Some(b) = newbits.pop() {
-                    n = 2*n + (b as i64);
-                } else {
-                    return None;
-                }
+                n = 2*n + (newbits.pop()? as i64);
             }
             newbits.reverse();
-            let mut children = vec![];
             for _ in 0..n {
-                children.push(Packet::parse(&mut newbits).unwrap());
+                children.push(parse_packet(&mut newbits)?);
             }
-            *raw_bits = newbits;
-            Some(Self{
-                children,
-            })
         } else {
             let mut n = 0;
             for _ in 0..15 {
-                if let Some(b) = newbits.pop() {
-                    n = 2*n + (b as i64);
-                } else {
-                    return None;
-                }
+                n = 2*n + (newbits.pop()? as i64);
             }
             for _ in 0..n {
-                if let Some(b) = newbits.pop() {
-                    v.push(b);
-                } else {
-                    return None;
-                }
+                v.push(newbits.pop()?);
             }
-            let mut children = vec![];
             newbits.reverse();
-            while let Some(packet) = Packet::parse(&mut v) {
+            while let Some(packet) = parse_packet(&mut v) {
                 children.push(packet);
             }
-            *raw_bits = newbits;
-            Some(Self{
-                children,
-            })
         }
+        *raw_bits = newbits;
+        Some(Operator{
+            length_type: if length_type_id { LengthType::Count } else { LengthType::Bits },
+            children,
+        })
     }
+}
 
-    fn add_versions(self: &Self) -> i32 {
-        let mut r = 0;
-        for c in &self.children {
-            r += c.add_versions();
+// Infix/call expression that compiles to a BITS transmission, e.g.
+// `sum(3, max(7, 8*2), 5 > 4)`. `+` and `*` fold into sum and product
+// packets, and `>`, `<` and `==` into gt, lt and eq.
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Number(i64),
+    Call(i32, Vec<Expr>),
+}
+
+impl Expr {
+    fn compile(source: &str, ops: &Registry) -> Result<Self, String> {
+        let mut parser = ExprParser{
+            tokens: tokenize(source)?,
+            pos: 0,
+            end: source.len(),
+            ops,
+        };
+        let expr = parser.comparison()?;
+        if let Some(&(col, _)) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected input at column {}", col + 1));
+        }
+        Ok(expr)
+    }
+
+    // Evaluated directly from the source, as a check on Packet::evaluate.
+    fn value(&self, ops: &Registry) -> BigInt {
+        match self {
+            Expr::Number(n) => BigInt::from(*n),
+            Expr::Call(type_id, args) => {
+                let values: Vec<BigInt> = args.iter().map(|a| a.value(ops)).collect();
+                ops.get(*type_id).unwrap().apply_big(&values)
+            },
+        }
+    }
+
+    fn to_packet(&self) -> Packet {
+        match self {
+            Expr::Number(n) => Packet::literal(0, *n),
+            Expr::Call(type_id, args) => {
+                Packet::operator(0, *type_id, args.iter().map(|a| a.to_packet()).collect())
+            },
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Open,
+    Close,
+    Comma,
+    Plus,
+    Star,
+    Greater,
+    Less,
+    Equal,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut r = vec![];
+    let chars: Vec<(usize, char)> = source.char_indices().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let (col, c) = chars[i];
+        i += 1;
+        let token = match c {
+            ' ' | '\t' => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            ',' => Token::Comma,
+            '+' => Token::Plus,
+            '*' => Token::Star,
+            '>' => Token::Greater,
+            '<' => Token::Less,
+            '=' => {
+                if chars.get(i).map(|&(_, c)| c) != Some('=') {
+                    return Err(format!("expected '==' at column {}", col + 1));
+                }
+                i += 1;
+                Token::Equal
+            },
+            '0'..='9' => {
+                let mut digits = String::from(c);
+                while let Some(&(_, d)) = chars.get(i).filter(|(_, d)| d.is_ascii_digit()) {
+                    digits.push(d);
+                    i += 1;
+                }
+                match digits.parse::<i64>() {
+                    Ok(n) => Token::Number(n),
+                    Err(_) => {
+                        return Err(format!("number too large at column {}", col + 1));
+                    },
+                }
+            },
+            'a'..='z' => {
+                let mut name = String::from(c);
+                while let Some(&(_, d)) = chars.get(i).filter(|(_, d)| d.is_ascii_lowercase()) {
+                    name.push(d);
+                    i += 1;
+                }
+                Token::Name(name)
+            },
+            _ => {
+                return Err(format!("unexpected '{}' at column {}", c, col + 1));
+            },
+        };
+        r.push((col, token));
+    }
+    Ok(r)
+}
+
+struct ExprParser<'r> {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+    ops: &'r Registry,
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.pos).map(|&(col, _)| col).unwrap_or(self.end) + 1
+    }
+
+    // Type ID of a named operation, checking it can take `n` arguments.
+    fn operation(&self, name: &str, n: usize, column: usize) -> Result<i32, String> {
+        let type_id = match self.ops.find(name) {
+            Some(type_id) => type_id,
+            None => { return Err(format!("unknown operator '{}' at column {}", name, column)); }
+        };
+        let arity = self.ops.get(type_id).unwrap().arity();
+        if !arity.allows(n) {
+            return Err(format!("{} takes {} arguments but got {} at column {}", name, arity, n, column));
+        }
+        Ok(type_id)
+    }
+
+    fn expect(&mut self, token: Token, what: &str) -> Result<(), String> {
+        if self.peek() == Some(&token) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected {} at column {}", what, self.column()))
+        }
+    }
+
+    // comparison := additive (('>' | '<' | '==') additive)?
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let left = self.additive()?;
+        let column = self.column();
+        let name = match self.peek() {
+            Some(Token::Greater) => "gt",
+            Some(Token::Less) => "lt",
+            Some(Token::Equal) => "eq",
+            _ => { return Ok(left); },
+        };
+        self.pos += 1;
+        let right = self.additive()?;
+        Ok(Expr::Call(self.operation(name, 2, column)?, vec![left, right]))
+    }
+
+    // additive := multiplicative ('+' multiplicative)*
+    fn additive(&mut self) -> Result<Expr, String> {
+        let column = self.column();
+        let mut terms = vec![self.multiplicative()?];
+        while self.peek() == Some(&Token::Plus) {
+            self.pos += 1;
+            terms.push(self.multiplicative()?);
+        }
+        if terms.len() == 1 {
+            return Ok(terms.pop().unwrap());
+        }
+        Ok(Expr::Call(self.operation("sum", terms.len(), column)?, terms))
+    }
+
+    // multiplicative := primary ('*' primary)*
+    fn multiplicative(&mut self) -> Result<Expr, String> {
+        let column = self.column();
+        let mut factors = vec![self.primary()?];
+        while self.peek() == Some(&Token::Star) {
+            self.pos += 1;
+            factors.push(self.primary()?);
+        }
+        if factors.len() == 1 {
+            return Ok(factors.pop().unwrap());
+        }
+        Ok(Expr::Call(self.operation("product", factors.len(), column)?, factors))
+    }
+
+    // primary := number | name '(' comparison (',' comparison)* ')' | '(' comparison ')'
+    fn primary(&mut self) -> Result<Expr, String> {
+        let column = self.column();
+        match self.peek().cloned() {
+            Some(Token::Number(n)) => {
+                self.pos += 1;
+                Ok(Expr::Number(n))
+            },
+            Some(Token::Open) => {
+                self.pos += 1;
+                let inner = self.comparison()?;
+                self.expect(Token::Close, "')'")?;
+                Ok(inner)
+            },
+            Some(Token::Name(name)) => {
+                if self.ops.find(&name).is_none() {
+                    return Err(format!("unknown operator '{}' at column {}", name, column));
+                }
+                self.pos += 1;
+                self.expect(Token::Open, "'('")?;
+                let mut args = vec![self.comparison()?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                    args.push(self.comparison()?);
+                }
+                self.expect(Token::Close, "')'")?;
+                Ok(Expr::Call(self.operation(&name, args.len(), column)?, args))
+            },
+            _ => Err(format!("expected a number, operator or '(' at column {}", column)),
         }
-        r
     }
 }
 
@@ -322,6 +1481,58 @@ mod tests{
         vec![false, false]
     }
 
+    // Runs a parse function over `raw` and leaves the unread bits behind in it.
+    fn parse_bits<T>(raw: &mut Vec<bool>, parse: fn(&mut BitCursor) -> Result<T, DecodeError>) -> Result<T, DecodeError> {
+        let mut bytes = vec![0; raw.len().div_ceil(8)];
+        for (i, &b) in raw.iter().enumerate() {
+            bytes[i / 8] |= (b as u8) << (7 - i % 8);
+        }
+        let mut cursor = BitCursor::new(&bytes, raw.len());
+        let r = parse(&mut cursor);
+        *raw = raw.split_off(cursor.pos);
+        r
+    }
+
+    fn lit(version: i32, value: u64) -> Packet {
+        Packet::literal(version, value as i64)
+    }
+
+    fn op(version: i32, type_id: i32, length_type: LengthType, children: Vec<Packet>) -> Packet {
+        Packet{
+            version,
+            type_id,
+            child: Type::Operator(Operator{
+                length_type,
+                children,
+            }),
+        }
+    }
+
+    // xorshift, so the generated packets are the same on every run
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn random_packet(rng: &mut Rng, depth: usize) -> Packet {
+        let version = rng.below(8) as i32;
+        if depth == 0 || rng.below(3) == 0 {
+            let width = rng.below(60);
+            return lit(version, rng.below(1 << width));
+        }
+        let type_id = [0, 1, 2, 3, 5, 6, 7][rng.below(7) as usize];
+        let length_type = if rng.below(2) == 0 { LengthType::Bits } else { LengthType::Count };
+        let n = if type_id >= 5 { 2 } else { 1 + rng.below(4) };
+        let children = (0..n).map(|_| random_packet(rng, depth - 1)).collect();
+        op(version, type_id, length_type, children)
+    }
+
     #[test]
     fn literal_from() {
         {
@@ -329,14 +1540,14 @@ mod tests{
                 false, false, false, true,
             ];
             let literal: Literal = Literal::from(raw_literal);
-            assert_eq!(literal.value(), 1);
+            assert_eq!(literal.value(), Some(1));
         }
         {
             let raw_literal = vec![
                 true, true, true, true,
             ];
             let literal: Literal = Literal::from(raw_literal);
-            assert_eq!(literal.value(), 15);
+            assert_eq!(literal.value(), Some(15));
         }
         {
             let raw_literal = vec![
@@ -344,7 +1555,7 @@ mod tests{
                 true, true, true, true,
             ];
             let literal: Literal = Literal::from(raw_literal);
-            assert_eq!(literal.value(), 255);
+            assert_eq!(literal.value(), Some(255));
         }
     }
 
@@ -355,7 +1566,7 @@ mod tests{
                 false, false, false, false, true,
                 false, false, false,
             ];
-            let lit = Literal::parse(&mut raw_bits).unwrap();
+            let lit = parse_bits(&mut raw_bits, Literal::parse).unwrap();
             assert_eq!(raw_bits.len(), 3);
             assert_eq!(lit.raw.len(), 4);
         }
@@ -366,15 +1577,15 @@ mod tests{
                 false, false, false, false, true,
                 false, false, false,
             ];
-            let lit = Literal::parse(&mut raw_bits).unwrap();
+            let lit = parse_bits(&mut raw_bits, Literal::parse).unwrap();
             assert_eq!(lit.raw.len(), 4);
             assert_eq!(raw_bits.len(), 13);
-            Literal::parse(&mut raw_bits);
+            parse_bits(&mut raw_bits, Literal::parse).unwrap();
             assert_eq!(raw_bits.len(), 8);
-            Literal::parse(&mut raw_bits);
+            parse_bits(&mut raw_bits, Literal::parse).unwrap();
             assert_eq!(raw_bits.len(), 3);
-            let r = Literal::parse(&mut raw_bits);
-            assert!(matches!(r, None));
+            let r = parse_bits(&mut raw_bits, Literal::parse);
+            assert!(r.is_err());
         }
         {
             let mut raw_bits: Vec<bool> = vec![
@@ -383,11 +1594,11 @@ mod tests{
                 false, false, false, false, true,
                 false, false, false,
             ];
-            let lit = Literal::parse(&mut raw_bits).unwrap();
+            let lit = parse_bits(&mut raw_bits, Literal::parse).unwrap();
             assert_eq!(lit.raw.len(), 12);
             assert_eq!(raw_bits.len(), 3);
-            let r = Literal::parse(&mut raw_bits);
-            assert!(matches!(r, None));
+            let r = parse_bits(&mut raw_bits, Literal::parse);
+            assert!(r.is_err());
         }
     }
 
@@ -399,7 +1610,7 @@ mod tests{
             raw_bits.extend(&packet_lit_15());
             raw_bits.extend(&filler2());
             println!("{:?}", raw_bits);
-            let op = Operator::parse(&mut raw_bits).unwrap();
+            let op = parse_bits(&mut raw_bits, Operator::parse).unwrap();
             assert_eq!(raw_bits.len(), 2);
             assert_eq!(op.children.len(), 1);
         }
@@ -414,7 +1625,7 @@ mod tests{
                 false, true, true, true, true,
                 false, false,
             ];
-            Operator::parse(&mut raw_bits);
+            parse_bits(&mut raw_bits, Operator::parse).unwrap();
             assert_eq!(raw_bits.len(), 2);
         }
     }
@@ -427,7 +1638,7 @@ mod tests{
             raw.extend(&type4());
             raw.extend(&literal_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
             assert_eq!(raw.len(), 2);
             assert_eq!(packet.version, 2);
             assert_eq!(packet.type_id, 4);
@@ -440,7 +1651,7 @@ mod tests{
             raw.extend(&length_type_id_0_11());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
             println!("{:?}", raw);
             assert_eq!(raw.len(), 2);
             assert_eq!(packet.version, 2);
@@ -454,7 +1665,7 @@ mod tests{
             raw.extend(&length_type_id_1_1());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
             println!("{:?}", raw);
             assert_eq!(raw.len(), 2);
             assert_eq!(packet.version, 2);
@@ -477,7 +1688,7 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
             assert_eq!(raw.len(), 2);
             assert_eq!(packet.version, 2);
             assert_eq!(packet.type_id, 5);
@@ -499,7 +1710,7 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
             assert_eq!(packet.add_versions(), 8);
         }
     }
@@ -515,8 +1726,8 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 45);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(45));
         }
         {
             let mut raw = vec![];
@@ -527,8 +1738,8 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 15*15*15);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(15*15*15));
         }
         {
             let mut raw = vec![];
@@ -539,8 +1750,8 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 15);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(15));
         }
         {
             let mut raw = vec![];
@@ -551,8 +1762,8 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 15);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(15));
         }
         {
             let mut raw = vec![];
@@ -562,8 +1773,8 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_14());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 1);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(1));
         }
         {
             let mut raw = vec![];
@@ -573,8 +1784,8 @@ mod tests{
             raw.extend(&packet_lit_14());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 0);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(0));
         }
         {
             let mut raw = vec![];
@@ -584,8 +1795,8 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_14());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 0);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(0));
         }
         {
             let mut raw = vec![];
@@ -595,8 +1806,8 @@ mod tests{
             raw.extend(&packet_lit_14());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 1);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(1));
         }
         {
             let mut raw = vec![];
@@ -606,8 +1817,8 @@ mod tests{
             raw.extend(&packet_lit_14());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 0);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(0));
         }
         {
             let mut raw = vec![];
@@ -617,8 +1828,435 @@ mod tests{
             raw.extend(&packet_lit_15());
             raw.extend(&packet_lit_15());
             raw.extend(&filler2());
-            let packet = Packet::parse(&mut raw).unwrap();
-            assert_eq!(packet.evaluate(), 1);
+            let packet = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert_eq!(packet.evaluate(&Registry::standard()), Ok(1));
+        }
+    }
+
+    #[test]
+    fn encode_fixtures() {
+        assert_eq!(lit(2, 14).encode().unwrap(), packet_lit_14());
+        assert_eq!(lit(2, 15).encode().unwrap(), packet_lit_15());
+        {
+            let mut raw = vec![];
+            raw.extend(&version2());
+            raw.extend(&type5());
+            raw.extend(&length_type_id_1_2());
+            raw.extend(&packet_lit_15());
+            raw.extend(&packet_lit_14());
+            let packet = op(2, 5, LengthType::Count, vec![lit(2, 15), lit(2, 14)]);
+            assert_eq!(packet.encode().unwrap(), raw);
+        }
+        {
+            let mut raw = vec![];
+            raw.extend(&version2());
+            raw.extend(&type5());
+            raw.extend(&length_type_id_0_11());
+            raw.extend(&packet_lit_15());
+            let packet = op(2, 5, LengthType::Bits, vec![lit(2, 15)]);
+            assert_eq!(packet.encode().unwrap(), raw);
+        }
+    }
+
+    #[test]
+    fn encode_too_long() {
+        let ops = Registry::standard();
+        let ones = vec!["1"; 3000].join(",");
+        let packet = Expr::compile(&format!("sum({})", ones), &ops).unwrap().to_packet();
+        let e = packet.to_hex().unwrap_err();
+        assert_eq!(e, EncodeError::TooLong(location(0, vec![]), 33000));
+        assert_eq!(e.to_string(), "bit 0 (root): children take 33000 bits, more than length type 0 allows (32767)");
+        let packet = op(0, 1, LengthType::Count, vec![lit(0, 2), op(0, 0, LengthType::Count, (0..2048).map(|_| lit(0, 1)).collect())]);
+        assert_eq!(packet.encode(), Err(EncodeError::TooMany(location(29, vec![1]), 2048)));
+        // 2047 children still fit in the count
+        let packet = Expr::compile(&format!("sum({})", vec!["1"; 2047].join(",")), &ops).unwrap().to_packet();
+        assert_eq!(Packet::from_hex(&packet.to_hex().unwrap()).unwrap().evaluate(&ops), Ok(2047));
+    }
+
+    #[test]
+    fn encode_literal_groups() {
+        let packet = lit(6, 2021);
+        assert_eq!(packet.to_hex().unwrap(), "D2FE28");
+        assert!(matches!(&packet.child, Type::Literal(l) if l.value() == Some(2021)));
+    }
+
+    #[test]
+    fn roundtrip_examples() {
+        for hex in [
+            "D2FE28",
+            "38006F45291200",
+            "EE00D40C823060",
+            "8A004A801A8002F478",
+            "620080001611562C8802118E34",
+            "C0015000016115A2E0802F182340",
+            "A0016C880162017C3686B18A3D4780",
+            "C200B40A82",
+            "9C0141080250320F1802104A08",
+        ] {
+            let packet = Packet::from_hex(hex).unwrap();
+            assert_eq!(packet.to_hex().unwrap(), hex);
+        }
+    }
+
+    #[test]
+    fn roundtrip_random() {
+        let mut rng = Rng(0x2021_1216);
+        for _ in 0..500 {
+            let packet = random_packet(&mut rng, 4);
+            let mut raw = packet.encode().unwrap();
+            let parsed = parse_bits(&mut raw, Packet::parse).unwrap();
+            assert!(raw.is_empty());
+            assert_eq!(parsed, packet);
+        }
+    }
+
+    fn compiled_value(source: &str) -> i64 {
+        let ops = Registry::standard();
+        let hex = Expr::compile(source, &ops).unwrap().to_packet().to_hex().unwrap();
+        Packet::from_hex(&hex).unwrap().evaluate(&ops).unwrap()
+    }
+
+    #[test]
+    fn compile_calls() {
+        assert_eq!(compiled_value("sum(1, 2)"), 3);
+        assert_eq!(compiled_value("product(6, 9)"), 54);
+        assert_eq!(compiled_value("min(7, 8, 9)"), 7);
+        assert_eq!(compiled_value("max(7, 8, 9)"), 9);
+        assert_eq!(compiled_value("lt(5, 15)"), 1);
+        assert_eq!(compiled_value("gt(5, 15)"), 0);
+        assert_eq!(compiled_value("eq(product(2, 2), sum(1, 3))"), 1);
+    }
+
+    #[test]
+    fn compile_infix() {
+        assert_eq!(Expr::compile("1 + 2 + 3", &Registry::standard()).unwrap(), Expr::Call(0, vec![
+            Expr::Number(1), Expr::Number(2), Expr::Number(3),
+        ]));
+        assert_eq!(compiled_value("2 + 3 * 4"), 14);
+        assert_eq!(compiled_value("(2 + 3) * 4"), 20);
+        assert_eq!(compiled_value("1 + 3 == 2 * 2"), 1);
+        assert_eq!(compiled_value("sum(3, max(7, 8*2), 5 > 4)"), 20);
+    }
+
+    #[test]
+    fn compile_matches_expression() {
+        for source in [
+            "sum(3, max(7, 8*2), 5 > 4)",
+            "product(1000000, 1000000) + 17",
+            "min(max(1, 2), 3, 0 == 0) * 12345678",
+            "9 < 10 + 0",
+            "product(4294967296, 4294967296, 4294967296)",
+        ] {
+            let ops = Registry::standard();
+            let expr = Expr::compile(source, &ops).unwrap();
+            let hex = expr.to_packet().to_hex().unwrap();
+            assert_eq!(Packet::from_hex(&hex).unwrap().big_evaluate(&ops, 0), Ok(expr.value(&ops)));
+        }
+    }
+
+    #[test]
+    fn compile_errors() {
+        let ops = Registry::standard();
+        assert_eq!(Expr::compile("sum(1, 2", &ops), Err(String::from("expected ')' at column 9")));
+        assert_eq!(Expr::compile("mod(1, 2)", &ops), Err(String::from("unknown operator 'mod' at column 1")));
+        assert_eq!(Expr::compile("gt(1, 2, 3)", &ops), Err(String::from("gt takes exactly 2 arguments but got 3 at column 1")));
+        assert_eq!(Expr::compile("1 = 2", &ops), Err(String::from("expected '==' at column 3")));
+        assert_eq!(Expr::compile("1 2", &ops), Err(String::from("unexpected input at column 3")));
+        assert_eq!(Expr::compile("-1", &ops), Err(String::from("unexpected '-' at column 1")));
+    }
+
+    fn location(offset: usize, path: Vec<usize>) -> Location {
+        Location{
+            offset,
+            path,
+        }
+    }
+
+    #[test]
+    fn decode_truncated() {
+        assert_eq!(parse_bits(&mut vec![true, false], Packet::parse), Err(DecodeError::TruncatedHeader(location(0, vec![]))));
+        assert_eq!(Packet::from_hex("D2"), Err(DecodeError::TruncatedLiteral(location(6, vec![]))));
+        let packet = op(0, 0, LengthType::Count, vec![
+            lit(0, 1),
+            op(0, 3, LengthType::Count, vec![lit(0, 2), lit(0, 3)]),
+        ]);
+        let mut raw = packet.encode().unwrap();
+        raw.truncate(62);
+        assert_eq!(parse_bits(&mut raw, Packet::parse), Err(DecodeError::TruncatedHeader(location(58, vec![1, 1]))));
+        let mut raw = packet.encode().unwrap();
+        raw.truncate(35);
+        assert_eq!(parse_bits(&mut raw, Packet::parse), Err(DecodeError::TruncatedLength(location(35, vec![1]))));
+    }
+
+    #[test]
+    fn decode_bad_length() {
+        let mut raw = op(0, 0, LengthType::Bits, vec![lit(0, 1), lit(0, 2)]).encode().unwrap();
+        raw.truncate(30);
+        assert_eq!(parse_bits(&mut raw, Packet::parse), Err(DecodeError::BadLength(location(7, vec![]), 22, 8)));
+    }
+
+    #[test]
+    fn decode_padding() {
+        assert_eq!(Packet::from_hex("D2FE29"), Err(DecodeError::TrailingPadding(location(23, vec![]))));
+        assert_eq!(Packet::from_hex("D2FG28"), Err(DecodeError::InvalidHex(location(12, vec![]), 'G')));
+    }
+
+    #[test]
+    fn evaluate_arity() {
+        let ops = Registry::standard();
+        let packet = op(0, 1, LengthType::Bits, vec![
+            op(0, 5, LengthType::Count, vec![lit(0, 1), lit(0, 2), lit(0, 3)]),
+        ]);
+        let e = packet.evaluate(&ops).unwrap_err();
+        assert_eq!(e, EvalError::WrongArity(location(22, vec![0]), String::from("gt"), 3, Arity::Exactly(2)));
+        assert_eq!(e.to_string(), "bit 22 (root → child 0): gt has 3 sub-packets but needs exactly 2");
+        let packet = Packet::from_hex(&op(0, 2, LengthType::Count, vec![]).to_hex().unwrap()).unwrap();
+        let e = packet.big_evaluate(&ops, 0).unwrap_err();
+        assert_eq!(e, EvalError::WrongArity(location(0, vec![]), String::from("min"), 0, Arity::AtLeast(1)));
+    }
+
+    #[test]
+    fn cursor_take() {
+        let bytes = [0b1010_0000, 0b1111_0000];
+        let mut cursor = BitCursor::new(&bytes, 12);
+        assert_eq!(cursor.read(3), Some(0b101));
+        let mut span = cursor.take(6).unwrap();
+        assert_eq!(cursor.pos, 9);
+        assert_eq!(span.read(6), Some(0b000001));
+        assert_eq!(span.bit(), None);
+        assert!(cursor.take(4).is_none());
+        assert_eq!(cursor.read(3), Some(0b111));
+        assert_eq!(cursor.bit(), None);
+    }
+
+    #[test]
+    fn legacy_matches_cursor() {
+        for packet in [bench_packet(2_500), random_packet(&mut Rng(0x2021_1216), 5)] {
+            let hex = packet.to_hex().unwrap();
+            let decoded = legacy::parse_packet(&mut legacy::hex_to_bits(&hex)).unwrap();
+            assert_eq!(decoded, Packet::from_hex(&hex).unwrap());
+        }
+    }
+
+    #[test]
+    fn display_tree() {
+        let packet = Packet::from_hex("C0015000016115A2E0802F182340").unwrap();
+        assert_eq!(packet.to_string(), "\
+v6 sum = 46 (length type 0: 84 bits) [bits 0..106]
+  v0 sum = 21 (length type 0: 22 bits) [bits 22..66]
+    v0 literal 10 [bits 44..55]
+    v6 literal 11 [bits 55..66]
+  v4 sum = 25 (length type 1: 2 sub-packets) [bits 66..106]
+    v7 literal 12 [bits 84..95]
+    v0 literal 13 [bits 95..106]
+");
+    }
+
+    #[test]
+    fn check_arity() {
+        let ops = Registry::standard();
+        assert_eq!(op(1, 2, LengthType::Count, vec![]).check(&ops, 0),
+            Err(EvalError::WrongArity(location(0, vec![]), String::from("min"), 0, Arity::AtLeast(1))));
+        let packet = op(1, 0, LengthType::Count, vec![lit(2, 1), op(3, 5, LengthType::Count, vec![lit(4, 2)])]);
+        assert_eq!(packet.check(&ops, 0),
+            Err(EvalError::WrongArity(location(29, vec![1]), String::from("gt"), 1, Arity::Exactly(2))));
+        let packet = Packet::from_hex("9C0141080250320F1802104A08").unwrap();
+        assert_eq!(packet.check(&ops, 0), Ok(packet.bit_len()));
+    }
+
+    #[test]
+    fn deep_tree() {
+        let depth = 200;
+        let mut packet = lit(0, 7);
+        for _ in 0..depth {
+            packet = op(0, 0, LengthType::Count, vec![packet]);
+        }
+        let tree = packet.to_string();
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!(lines.len(), depth + 1);
+        for (i, line) in lines[..depth].iter().enumerate() {
+            assert_eq!(line.trim_start(), format!("v0 sum = 7 (length type 1: 1 sub-packets) [bits {}..{}]", 18 * i, 18 * depth + 11));
+        }
+        assert_eq!(lines[depth].trim_start(), format!("v0 literal 7 [bits {}..{}]", 18 * depth, 18 * depth + 11));
+
+        let packet = op(1, 0, LengthType::Count, vec![op(2, 5, LengthType::Count, vec![lit(3, 1)])]);
+        assert_eq!(packet.to_dot(&Registry::standard()), r#"digraph packet {
+    p0 [shape=box, label="v1 sum\n= ?"];
+    p1 [shape=box, label="v2 gt\n= ?"];
+    p2 [shape=ellipse, label="v3 1"];
+    p1 -> p2;
+    p0 -> p1;
+}
+"#);
+    }
+
+    #[test]
+    fn dot_graph() {
+        let packet = Packet::from_hex("9C0141080250320F1802104A08").unwrap();
+        assert_eq!(packet.to_dot(&Registry::standard()), r#"digraph packet {
+    p0 [shape=box, label="v4 eq\n= 1"];
+    p1 [shape=box, label="v2 sum\n= 4"];
+    p2 [shape=ellipse, label="v2 1"];
+    p1 -> p2;
+    p3 [shape=ellipse, label="v4 3"];
+    p1 -> p3;
+    p0 -> p1;
+    p4 [shape=box, label="v6 product\n= 4"];
+    p5 [shape=ellipse, label="v0 2"];
+    p4 -> p5;
+    p6 [shape=ellipse, label="v2 2"];
+    p4 -> p6;
+    p0 -> p4;
+}
+"#);
+    }
+
+    fn wide_lit(version: i32, nibbles: usize) -> Packet {
+        Packet{
+            version,
+            type_id: 4,
+            child: Type::Literal(Literal::from(vec![true; 4 * nibbles])),
+        }
+    }
+
+    #[test]
+    fn wide_literals() {
+        let packet = wide_lit(1, 20);
+        let hex = packet.to_hex().unwrap();
+        let decoded = Packet::from_hex(&hex).unwrap();
+        let ops = Registry::standard();
+        assert_eq!(decoded.big_evaluate(&ops, 0), Ok((BigInt::from(1) << 80) - 1));
+        assert_eq!(decoded.evaluate(&ops), Err(EvalError::Overflow(location(0, vec![]), String::from("literal"))));
+        assert_eq!(wide_lit(1, 15).evaluate(&ops), Ok(i64::MAX >> 3));
+    }
+
+    #[test]
+    fn checked_overflow() {
+        let big = 1 << 40;
+        let packet = op(0, 0, LengthType::Count, vec![
+            lit(0, 1),
+            op(0, 1, LengthType::Bits, vec![lit(0, big), lit(0, big)]),
+        ]);
+        let ops = Registry::standard();
+        let e = packet.evaluate(&ops).unwrap_err();
+        assert_eq!(e, EvalError::Overflow(location(29, vec![1]), String::from("product")));
+        assert_eq!(e.to_string(), "bit 29 (root → child 1): product overflows i64");
+        assert_eq!(packet.big_evaluate(&ops, 0), Ok(BigInt::from(big) * big + 1));
+        let packet = op(0, 3, LengthType::Count, vec![lit(0, 1), lit(0, big)]);
+        assert_eq!(packet.evaluate(&Registry::standard()), Ok(big as i64));
+    }
+
+    #[test]
+    fn stream_back_to_back() {
+        let mut raw = lit(6, 2021).encode().unwrap();
+        raw.extend(op(1, 0, LengthType::Count, vec![lit(2, 1), lit(3, 2)]).encode().unwrap());
+        raw.extend(lit(0, 0).encode().unwrap());
+        raw.extend(vec![false; 8]);
+        let hex: String = raw.chunks(4)
+            .map(|c| format!("{:X}", c.iter().fold(0, |acc, &b| 2*acc + b as u8) << (4 - c.len())))
+            .collect();
+        let mut packets = vec![];
+        decode_stream(&hex, 1, |p| packets.push(p)).unwrap();
+        assert_eq!(packets, vec![
+            lit(6, 2021),
+            op(1, 0, LengthType::Count, vec![lit(2, 1), lit(3, 2)]),
+            lit(0, 0),
+        ]);
+    }
+
+    #[test]
+    fn stream_padded() {
+        // two ordinary transmissions, each padded to whole bytes
+        let mut packets = vec![];
+        decode_stream("D2FE2838006F45291200", 8, |p| packets.push(p)).unwrap();
+        let second = || Packet::from_hex("38006F45291200").unwrap();
+        assert_eq!(packets, vec![lit(6, 2021), second()]);
+        // the second ends 7 bits short of a byte, more than a hex digit
+        packets.clear();
+        decode_stream("38006F45291200D2FE28", 8, |p| packets.push(p)).unwrap();
+        assert_eq!(packets, vec![second(), lit(6, 2021)]);
+        // 11-bit literals padded to hex digits
+        packets.clear();
+        decode_stream("102102", 4, |p| packets.push(p)).unwrap();
+        assert_eq!(packets, vec![lit(0, 1), lit(0, 1)]);
+    }
+
+    #[test]
+    fn stream_error() {
+        let mut packets = vec![];
+        // the second transmission starts at bit 24 but is cut off
+        let r = decode_stream("D2FE28D2", 8, |p| packets.push(p));
+        assert_eq!(packets, vec![lit(6, 2021)]);
+        assert_eq!(r, Err(DecodeError::TruncatedLiteral(location(30, vec![]))));
+        // non-zero padding between two transmissions
+        packets.clear();
+        let r = decode_stream("D2FE2938006F45291200", 8, |p| packets.push(p));
+        assert_eq!(packets, vec![lit(6, 2021)]);
+        assert_eq!(r, Err(DecodeError::TrailingPadding(location(23, vec![]))));
+        // unpadded packets read as byte-padded: the second starts inside the
+        // first's padding
+        packets.clear();
+        let r = decode_stream("D2FE2E97F140", 8, |p| packets.push(p));
+        assert_eq!(r, Err(DecodeError::TrailingPadding(location(21, vec![]))));
+        packets.clear();
+        decode_stream("D2FE2E97F140", 1, |p| packets.push(p)).unwrap();
+        assert_eq!(packets, vec![lit(6, 2021), lit(6, 2021)]);
+    }
+
+    struct Subtract;
+
+    impl Operation for Subtract {
+        fn name(&self) -> &str {
+            "sub"
+        }
+
+        fn arity(&self) -> Arity {
+            Arity::Exactly(2)
+        }
+
+        fn apply(&self, values: &[i64]) -> Option<i64> {
+            values[0].checked_sub(values[1])
         }
+
+        fn apply_big(&self, values: &[BigInt]) -> BigInt {
+            &values[0] - &values[1]
+        }
+    }
+
+    #[test]
+    fn registry_custom_operations() {
+        let mut ops = Registry::standard();
+        ops.register(5, Subtract);
+        ops.register(7, Builtin{
+            name: "xor",
+            arity: Arity::AtLeast(1),
+            apply: |v| Some(v.iter().fold(0, |acc, x| acc ^ x)),
+            apply_big: |v| v.iter().fold(BigInt::default(), |acc, x| acc ^ x),
+        });
+        let expr = Expr::compile("sub(10, xor(12, 10, 1)) + 3 < 7", &ops).unwrap();
+        let packet = Packet::from_hex(&expr.to_packet().to_hex().unwrap()).unwrap();
+        assert_eq!(packet.evaluate(&ops), Ok(1));
+        let tree = packet.tree(&ops).to_string();
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!(lines[1], "  v0 sum = 6 (length type 1: 2 sub-packets) [bits 18..127]");
+        assert!(lines[2].starts_with("    v0 sub = 3 (length type 1: 2 sub-packets)"), "{}", tree);
+        assert!(lines[4].starts_with("      v0 xor = 7 (length type 1: 3 sub-packets)"), "{}", tree);
+        let packet = op(0, 5, LengthType::Count, vec![lit(0, 2), lit(0, 7)]);
+        assert_eq!(packet.big_evaluate(&ops, 0), Ok(BigInt::from(-5)));
+        assert_eq!(Expr::compile("1 > 2", &ops), Err(String::from("unknown operator 'gt' at column 3")));
+    }
+
+    #[test]
+    fn registry_unknown_type() {
+        let mut ops = Registry::new();
+        ops.register(0, Subtract);
+        let packet = op(0, 0, LengthType::Count, vec![
+            lit(0, 9),
+            op(0, 3, LengthType::Count, vec![lit(0, 2)]),
+        ]);
+        let e = packet.evaluate(&ops).unwrap_err();
+        assert_eq!(e, EvalError::UnknownType(location(29, vec![1]), 3));
+        assert_eq!(e.to_string(), "bit 29 (root → child 1): no operation registered for type ID 3");
+        assert_eq!(ops.name(3), "unknown");
     }
 }
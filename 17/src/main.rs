@@ -1,53 +1,514 @@
-use std::{io, io::Read};
-use regex::Regex;
+use std::{env, fmt, io, io::Read, process};
 
-const PATTERN: &str = r"(?m)^target area: x=(-?\d+)\.\.(-?\d+), y=(-?\d+)\.\.(-?\d+)$";
 fn main() {
     let mut buf = String::new();
     io::stdin().read_to_string(&mut buf).expect("Problem reading input");
-    let target = Target::from(buf.trim());
+    let target = match Target::parse(&buf) {
+        Ok((target, warnings)) => {
+            for w in warnings {
+                eprintln!("warning: {}", w);
+            }
+            target
+        },
+        Err(e) => {
+            eprintln!("Invalid target area: {}", e);
+            process::exit(1);
+        },
+    };
+
+    let args: Vec<String> = env::args().collect();
+    let mode = args.get(1).map(|s| s.as_str());
+    let svg = args.iter().any(|a| a == "--svg");
+    let (physics, window) = match parse_physics(&args) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    if mode == Some("--trace") {
+        let velocity = match args.get(2).and_then(|s| parse_velocity(s)) {
+            Some(velocity) => velocity,
+            None => {
+                eprintln!("Usage: seventeen --trace VX,VY [--svg] < input.txt");
+                process::exit(1);
+            },
+        };
+        trace(&target, &physics, velocity, svg);
+        return;
+    }
+    if mode == Some("--scatter") {
+        match launches(&target, &physics, window) {
+            Some(hits) => {
+                let velocities: Vec<(isize, isize)> = hits.iter().map(|&(v, _)| v).collect();
+                print!("{}", if svg { scatter_svg(&velocities) } else { scatter(&velocities) });
+            },
+            None => {
+                eprintln!("Infinitely many velocities reach the target");
+                process::exit(1);
+            },
+        }
+        return;
+    }
+    let solution = if mode == Some("--sweep") || physics != Physics::default() || window.is_some() {
+        // the closed form only holds for the standard rules
+        if let Some(((x0, x1), (y0, y1))) = search_window(&target, &physics, window) {
+            println!("Searching x velocities {}..={}, y velocities {}..={}", x0, x1, y0, y1);
+        }
+        sweep(&target, &physics, window)
+    } else {
+        solve(&target)
+    };
+    match solution {
+        Some((max_y, count)) => {
+            println!("Highest y position: {}", max_y);
+            println!("{} distinct initial velocity values", count);
+        },
+        None => {
+            println!("Highest y position: unbounded");
+            println!("Infinitely many distinct initial velocity values");
+        },
+    }
+}
+
+// Simulates every launch velocity that could reach the target.
+fn sweep(target: &Target, physics: &Physics, window: Option<isize>) -> Option<(isize, usize)> {
+    let hits = launches(target, physics, window)?;
+    let max_y = hits.iter().map(|&(_, y)| y).max().unwrap_or(0);
+    Some((max_y, hits.len()))
+}
+
+// Each launch velocity that hits the target, with the highest y it reaches
+// on the way.
+fn launches(target: &Target, physics: &Physics, window: Option<isize>) -> Option<Vec<((isize, isize), isize)>> {
+    let ((x0, x1), (y0, y1)) = search_window(target, physics, window)?;
+    let mut hits = vec![];
+    for init_y in y0..=y1 {
+        for init_x in x0..=x1 {
+            let probe = Probe {
+                position: (0, 0),
+                x_velocity: init_x,
+                y_velocity: init_y,
+            };
+            // the launch position itself doesn't count as a hit
+            let y = probe.step(physics).highest_y(target, physics, 0);
+            if let Some(v) = y {
+                hits.push(((init_x, init_y), v));
+            }
+        }
+    }
+    Some(hits)
+}
+
+// The x and y velocity ranges to simulate: |v| <= window if given, and
+// otherwise the ranges that can hit under the standard rules, which are None
+// when infinitely many launches hit. Under any other physics those ranges
+// can miss hits, so it always comes with a window.
+fn search_window(target: &Target, physics: &Physics, window: Option<isize>) -> Option<((isize, isize), (isize, isize))> {
+    if let Some(n) = window {
+        return Some(((-n, n), (-n, n)));
+    }
+    assert!(*physics == Physics::default(), "other physics needs a window");
+    if target.unbounded() {
+        return None;
+    }
+    Some(((target.x0.min(0), target.x1.max(0)), target.y_velocities()))
+}
+
+// Reads --gravity, --drag, --wind, --terminal and --window, each followed by
+// an integer, from anywhere in the arguments. Searching under anything but
+// the standard physics needs a window.
+fn parse_physics(args: &[String]) -> Result<(Physics, Option<isize>), String> {
+    let mut physics = Physics::default();
+    let mut window = None;
+    let mut i = 1;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if !["--gravity", "--drag", "--wind", "--terminal", "--window"].contains(&flag) {
+            i += 1;
+            continue;
+        }
+        let value = match args.get(i + 1).map(|s| s.parse::<isize>()) {
+            Some(Ok(value)) => value,
+            Some(Err(_)) => { return Err(format!("{} expects an integer, got '{}'", flag, args[i + 1])); },
+            None => { return Err(format!("{} expects an integer", flag)); },
+        };
+        match flag {
+            "--gravity" if value < 0 => { return Err(String::from("--gravity must not be negative")); },
+            "--gravity" => physics.gravity = value,
+            "--drag" if value < 0 => { return Err(String::from("--drag must not be negative")); },
+            "--drag" => physics.drag = value,
+            "--wind" => physics.wind = value,
+            "--terminal" if value < 1 => { return Err(String::from("--terminal must be at least 1")); },
+            "--terminal" => physics.terminal = Some(value),
+            "--window" if value < 0 => { return Err(String::from("--window must not be negative")); },
+            _ => window = Some(value),
+        }
+        i += 2;
+    }
+    if physics != Physics::default() && window.is_none() && args.get(1).map(|s| s.as_str()) != Some("--trace") {
+        return Err(String::from("--gravity, --drag, --wind and --terminal need --window N to bound the velocities searched"));
+    }
+    Ok((physics, window))
+}
+
+fn parse_velocity(s: &str) -> Option<(isize, isize)> {
+    let (x, y) = s.split_once(',')?;
+    Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+}
+
+// Lists each step of a launch and draws its path.
+fn trace(target: &Target, physics: &Physics, velocity: (isize, isize), svg: bool) {
+    let probe = Probe {
+        position: (0, 0),
+        x_velocity: velocity.0,
+        y_velocity: velocity.1,
+    };
+    let (path, hit) = probe.trajectory(target, physics);
+    if svg {
+        print!("{}", path_svg(target, &path));
+        return;
+    }
+    for (n, (x, y)) in path.iter().enumerate().skip(1) {
+        println!("step {}: x={}, y={}", n, x, y);
+    }
+    if hit {
+        println!("Hits the target at step {}", path.len() - 1);
+    } else {
+        println!("Misses the target");
+    }
+    println!();
+    print!("{}", path_grid(target, &path));
+}
+
+// Smallest box holding the target and all the points.
+fn bounds(target: &Target, points: &[(isize, isize)]) -> (isize, isize, isize, isize) {
+    points.iter().fold((target.x0, target.x1, target.y0, target.y1), |(x0, x1, y0, y1), &(x, y)| {
+        (x0.min(x), x1.max(x), y0.min(y), y1.max(y))
+    })
+}
+
+// Drawn like the puzzle statement: S for the launcher, # for each position
+// and T for the target, with y increasing up the page.
+fn path_grid(target: &Target, path: &[(isize, isize)]) -> String {
+    let (x0, x1, y0, y1) = bounds(target, path);
+    let mut r = String::new();
+    for y in (y0..=y1).rev() {
+        for x in x0..=x1 {
+            r.push(if (x, y) == (0, 0) {
+                'S'
+            } else if path.contains(&(x, y)) {
+                '#'
+            } else if (target.x0..=target.x1).contains(&x) && (target.y0..=target.y1).contains(&y) {
+                'T'
+            } else {
+                '.'
+            });
+        }
+        r.push('\n');
+    }
+    r
+}
+
+// Opening tag for a drawing of x0..=x1 by y0..=y1 in puzzle coordinates,
+// flipped so y increases upwards, with a one-unit margin.
+fn svg_open(x0: isize, x1: isize, y0: isize, y1: isize) -> String {
+    format!("<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{} {} {} {}\">\n",
+        x0 - 1, -y1 - 1, x1 - x0 + 2, y1 - y0 + 2)
+}
 
+fn path_svg(target: &Target, path: &[(isize, isize)]) -> String {
+    let (x0, x1, y0, y1) = bounds(target, path);
+    let mut r = svg_open(x0, x1, y0, y1);
+    r.push_str(&format!("  <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"#cde\" stroke=\"#468\" vector-effect=\"non-scaling-stroke\"/>\n",
+        target.x0, -target.y1, target.x1 - target.x0, target.y1 - target.y0));
+    let points: Vec<String> = path.iter().map(|(x, y)| format!("{},{}", x, -y)).collect();
+    r.push_str(&format!("  <polyline points=\"{}\" fill=\"none\" stroke=\"#c33\" vector-effect=\"non-scaling-stroke\"/>\n",
+        points.join(" ")));
+    for (x, y) in path {
+        r.push_str(&format!("  <circle cx=\"{}\" cy=\"{}\" r=\"0.3\" fill=\"#c33\"/>\n", x, -y));
+    }
+    r.push_str("</svg>\n");
+    r
+}
+
+// Every valid launch velocity as a # against x velocity across and y
+// velocity up the page.
+fn scatter(velocities: &[(isize, isize)]) -> String {
+    if velocities.is_empty() {
+        return String::from("No launch velocity reaches the target\n");
+    }
+    let (x0, x1, y0, y1) = velocity_bounds(velocities);
+    let mut r = format!("x velocity {}..{}, y velocity {}..{}\n", x0, x1, y0, y1);
+    for y in (y0..=y1).rev() {
+        for x in x0..=x1 {
+            r.push(if velocities.contains(&(x, y)) { '#' } else { '.' });
+        }
+        r.push('\n');
+    }
+    r
+}
+
+fn scatter_svg(velocities: &[(isize, isize)]) -> String {
+    if velocities.is_empty() {
+        return svg_open(0, 0, 0, 0) + "</svg>\n";
+    }
+    let (x0, x1, y0, y1) = velocity_bounds(velocities);
+    let mut r = svg_open(x0, x1, y0, y1);
+    for (x, y) in velocities {
+        r.push_str(&format!("  <rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" fill=\"#468\"/>\n", x, -y));
+    }
+    r.push_str("</svg>\n");
+    r
+}
+
+fn velocity_bounds(velocities: &[(isize, isize)]) -> (isize, isize, isize, isize) {
+    let (x, y) = velocities[0];
+    velocities.iter().fold((x, x, y, y), |(x0, x1, y0, y1), &(x, y)| {
+        (x0.min(x), x1.max(x), y0.min(y), y1.max(y))
+    })
+}
+
+// Closed-form solver. After n steps a probe launched with (vx, vy) is at
+//   y = arc(vy, n)
+//   x = arc(vx, n) while n <= |vx|, and arc(vx, |vx|) from then on
+// (mirrored for vx < 0), so for each vy the steps spent in the target's rows
+// are found directly, and for each of those steps the x velocities landing in
+// its columns form a few ranges.
+fn solve(target: &Target) -> Option<(isize, usize)> {
+    if target.unbounded() {
+        return None;
+    }
     let mut max_y = 0;
-    let mut velocities: Vec<(isize, isize)> = vec![];
-    if target.x1 > 0 {
-        for init_y in target.y0..=-target.y0 {
-            for init_x in (1..=target.x1).rev() {
-                let probe = Probe {
-                    position: (0, 0),
-                    x_velocity: init_x,
-                    y_velocity: init_y,
-                };
-                let y = probe.highest_y(&target, 0);
-                if let Some(v) = y {
-                    velocities.push((init_x, init_y));
-                    max_y = max_y.max(v);
+    let mut count = 0;
+    let sides: Vec<_> = target.sides().into_iter()
+        .map(|(dir, near, far)| (dir, near, far, resting(near, far)))
+        .collect();
+    let (lo, hi) = target.y_velocities();
+    let mut covered = vec![];
+    for vy in lo..=hi {
+        covered.clear();
+        for (first, last) in steps_between(vy, target.y0, target.y1) {
+            for n in first..=last {
+                let t = n * (n - 1) / 2;
+                let mut added = 0;
+                if target.x0 <= 0 && target.x1 >= 0 {
+                    added += cover(&mut covered, (0, 0));
+                }
+                for &(dir, near, far, resting) in &sides {
+                    let moving = (div_ceil(near + t, n).max(n), (far + t).div_euclid(n));
+                    let stopped = (resting.0, resting.1.min(n - 1));
+                    for (a, b) in [moving, stopped] {
+                        added += cover(&mut covered, if dir > 0 { (a, b) } else { (-b, -a) });
+                    }
                 }
+                if added > 0 {
+                    // highest point reached before first entering the target
+                    max_y = max_y.max(arc(vy, n.min(vy.max(0))));
+                }
+                count += added;
             }
         }
     }
-    println!("Highest y position: {}", max_y);
-    println!("{} distinct initial velocity values", velocities.len());
+    Some((max_y, count))
+}
+
+// Height after n steps of a launch with velocity v, slowing by 1 each step.
+fn arc(v: isize, n: isize) -> isize {
+    n * v - n * (n - 1) / 2
+}
+
+// The steps n >= 1 at which arc(v, n) >= bound, a run either side of the peak.
+fn steps_reaching(v: isize, bound: isize) -> Option<(isize, isize)> {
+    let peak = v.max(1);
+    if arc(v, peak) < bound {
+        return None;
+    }
+    // roots of n^2 - (2v + 1)n + 2*bound = 0, then corrected for rounding
+    let b = (2 * v + 1) as f64;
+    let root = (b * b - 8.0 * bound as f64).max(0.0).sqrt();
+    let mut first = (((b - root) / 2.0).ceil() as isize).clamp(1, peak);
+    while first > 1 && arc(v, first - 1) >= bound {
+        first -= 1;
+    }
+    while arc(v, first) < bound {
+        first += 1;
+    }
+    let mut last = (((b + root) / 2.0).floor() as isize).max(peak);
+    while arc(v, last + 1) >= bound {
+        last += 1;
+    }
+    while arc(v, last) < bound {
+        last -= 1;
+    }
+    Some((first, last))
+}
+
+// The runs of steps at which arc(v, n) lies within lo..=hi: at most one on
+// the way up and one on the way down.
+fn steps_between(v: isize, lo: isize, hi: isize) -> Vec<(isize, isize)> {
+    match (steps_reaching(v, lo), steps_reaching(v, hi + 1)) {
+        (None, _) => vec![],
+        (Some(reach), None) => vec![reach],
+        (Some(reach), Some(over)) => {
+            vec![(reach.0, over.0 - 1), (over.1 + 1, reach.1)]
+                .into_iter()
+                .filter(|(first, last)| first <= last)
+                .collect()
+        },
+    }
+}
+
+// Speeds that come to rest between near and far from the launcher.
+fn resting(near: isize, far: isize) -> (isize, isize) {
+    (triangle_root(near - 1) + 1, triangle_root(far))
+}
+
+// Largest k >= 0 with k(k+1)/2 <= x.
+fn triangle_root(x: isize) -> isize {
+    if x < 0 {
+        return -1;
+    }
+    let mut k = ((2.0 * x as f64).sqrt()) as isize;
+    while k * (k + 1) / 2 > x {
+        k -= 1;
+    }
+    while (k + 1) * (k + 2) / 2 <= x {
+        k += 1;
+    }
+    k
+}
+
+fn div_ceil(a: isize, b: isize) -> isize {
+    -(-a).div_euclid(b)
+}
+
+// Adds lo..=hi to a set of disjoint ranges, returning how many values are new.
+fn cover(ranges: &mut Vec<(isize, isize)>, (lo, hi): (isize, isize)) -> usize {
+    if lo > hi {
+        return 0;
+    }
+    let (mut lo, mut hi) = (lo, hi);
+    let mut added = hi - lo + 1;
+    ranges.retain(|&(a, b)| {
+        if b < lo - 1 || a > hi + 1 {
+            return true;
+        }
+        added -= (b.min(hi) - a.max(lo) + 1).max(0);
+        lo = lo.min(a);
+        hi = hi.max(b);
+        false
+    });
+    ranges.push((lo, hi));
+    added as usize
 }
 
 struct Target {
     x0: isize, x1: isize, y0: isize, y1: isize,
 }
 
-impl From<&str> for Target {
-    fn from(s: &str) -> Self {
-        let r: Regex = Regex::new(PATTERN).unwrap();
-        if let Some(caps) = r.captures(s) {
-            Self{
-                x0: caps[1].parse::<isize>().unwrap(),
-                x1: caps[2].parse::<isize>().unwrap(),
-                y0: caps[3].parse::<isize>().unwrap(),
-                y1: caps[4].parse::<isize>().unwrap(),
+#[derive(Debug, PartialEq)]
+enum TargetError {
+    Empty,
+    MissingAxis(char),
+    DuplicateAxis(char),
+    UnknownAxis(String),
+    // axis, the text that should have been a range
+    BadRange(char, String),
+    // axis, the text that should have been a bound
+    NotAnInteger(char, String),
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TargetError::Empty => write!(f, "input is empty"),
+            TargetError::MissingAxis(axis) => write!(f, "missing {} range", axis),
+            TargetError::DuplicateAxis(axis) => write!(f, "{} range given more than once", axis),
+            TargetError::UnknownAxis(name) => write!(f, "unknown axis '{}', expected x or y", name),
+            TargetError::BadRange(axis, text) => write!(f, "{} range '{}' is not of the form a..b", axis, text),
+            TargetError::NotAnInteger(axis, text) => write!(f, "{} bound '{}' is not an integer", axis, text),
+        }
+    }
+}
+
+impl Target {
+    // Reads "target area: x=20..30, y=-10..-5", allowing whitespace around
+    // any part, the axes in either order and the "target area:" prefix to be
+    // left out. A range written high to low is swapped, with a warning.
+    fn parse(s: &str) -> Result<(Self, Vec<String>), TargetError> {
+        let mut s = s.trim();
+        if let Some(rest) = s.strip_prefix("target area") {
+            s = rest.trim_start();
+            s = s.strip_prefix(':').unwrap_or(s);
+        }
+        if s.trim().is_empty() {
+            return Err(TargetError::Empty);
+        }
+        let mut ranges: [Option<(isize, isize)>; 2] = [None, None];
+        let mut warnings = vec![];
+        for part in s.split(',') {
+            let (name, range) = part.split_once('=').unwrap_or((part, ""));
+            let axis = match name.trim() {
+                "x" => 'x',
+                "y" => 'y',
+                name => { return Err(TargetError::UnknownAxis(name.to_string())); },
+            };
+            let (lo, hi) = match range.split_once("..") {
+                Some(bounds) => bounds,
+                None => { return Err(TargetError::BadRange(axis, range.trim().to_string())); },
+            };
+            let bound = |text: &str| text.trim().parse::<isize>()
+                .map_err(|_| TargetError::NotAnInteger(axis, text.trim().to_string()));
+            let (mut lo, mut hi) = (bound(lo)?, bound(hi)?);
+            if lo > hi {
+                warnings.push(format!("{} range {}..{} is reversed, using {}..{}", axis, lo, hi, hi, lo));
+                (lo, hi) = (hi, lo);
             }
-        } else {
-            Self{
-                x0: 0, x1: 0, y0: 0, y1: 0,
+            let slot = &mut ranges[(axis == 'y') as usize];
+            if slot.is_some() {
+                return Err(TargetError::DuplicateAxis(axis));
             }
+            *slot = Some((lo, hi));
+        }
+        let (x0, x1) = ranges[0].ok_or(TargetError::MissingAxis('x'))?;
+        let (y0, y1) = ranges[1].ok_or(TargetError::MissingAxis('y'))?;
+        Ok((Self{ x0, x1, y0, y1 }, warnings))
+    }
+
+    // The parts of the target right and left of the launcher, as
+    // (direction, near, far) with near >= 1 measured away from x=0.
+    fn sides(&self) -> Vec<(isize, isize, isize)> {
+        let mut r = vec![];
+        if self.x1 >= 1 {
+            r.push((1, self.x0.max(1), self.x1));
         }
+        if self.x0 <= -1 {
+            r.push((-1, (-self.x1).max(1), -self.x0));
+        }
+        r
+    }
+
+    // A probe launched high enough always comes back down through y=0, so if
+    // the target spans that height and some x velocity stays inside its
+    // columns for good, every higher launch hits too.
+    fn unbounded(&self) -> bool {
+        let stays = (self.x0 <= 0 && self.x1 >= 0)
+            || self.sides().iter().any(|&(_, near, far)| {
+                let (slowest, fastest) = resting(near, far);
+                slowest <= fastest
+            });
+        self.y0 <= 0 && self.y1 >= 0 && stays
+    }
+
+    // Bounds on the y velocities that can reach a target that isn't unbounded.
+    fn y_velocities(&self) -> (isize, isize) {
+        let mut hi = (-self.y0 - 1).max(self.y1);
+        if self.y0 <= 0 && self.y1 >= 0 {
+            // hits come while the probe still drifts, within |x| steps
+            hi += self.x0.abs().max(self.x1.abs());
+        }
+        (self.y0.min(0), hi)
     }
 }
 
@@ -57,16 +518,40 @@ struct Probe {
     y_velocity: isize,
 }
 
+// How velocities change after each step. Drag slows horizontal motion
+// towards zero without reversing it, wind then pushes it sideways, and
+// gravity pulls the vertical velocity down to at most the terminal velocity.
+#[derive(Debug, PartialEq)]
+struct Physics {
+    gravity: isize,
+    drag: isize,
+    wind: isize,
+    terminal: Option<isize>,
+}
+
+impl Default for Physics {
+    fn default() -> Self {
+        Self{
+            gravity: 1,
+            drag: 1,
+            wind: 0,
+            terminal: None,
+        }
+    }
+}
+
 impl Probe {
-    fn step(&self) -> Self {
+    fn step(&self, physics: &Physics) -> Self {
+        let x_velocity = match self.x_velocity {
+            1.. => (self.x_velocity - physics.drag).max(0),
+            0 => 0,
+            _ => (self.x_velocity + physics.drag).min(0),
+        };
+        let y_velocity = self.y_velocity - physics.gravity;
         Self{
             position: ( self.position.0 + self.x_velocity, self.position.1 + self.y_velocity),
-            x_velocity: match self.x_velocity {
-                1.. => self.x_velocity - 1,
-                0 => 0,
-                _ => self.x_velocity + 1,
-            },
-            y_velocity: self.y_velocity - 1,
+            x_velocity: x_velocity + physics.wind,
+            y_velocity: physics.terminal.map_or(y_velocity, |t| y_velocity.max(-t)),
         }
     }
 
@@ -75,22 +560,286 @@ impl Probe {
         && self.position.1.clamp(target.y0, target.y1) == self.position.1
     }
 
-    fn doomed(&self, target: &Target) -> bool {
+    // Drag never reverses the x velocity, so only wind can turn the probe back
+    // towards the target; gravity is never negative, so once falling (or
+    // still, without gravity) it can't climb back up.
+    fn doomed(&self, target: &Target, physics: &Physics) -> bool {
         !self.intersecting(target) &&
         (
-            (self.position.0 < target.x0 && self.x_velocity <= 0)
-            || (self.position.0 > target.x1 && self.x_velocity >= 0)
-            || self.position.1 < target.y0
+            (self.position.0 < target.x0 && self.x_velocity <= 0 && physics.wind <= 0)
+            || (self.position.0 > target.x1 && self.x_velocity >= 0 && physics.wind >= 0)
+            || (self.position.1 < target.y0 && self.y_velocity <= 0)
+            || (self.position.1 > target.y1 && self.y_velocity >= 0 && physics.gravity == 0)
         )
     }
 
-    fn highest_y(&self, target: &Target, current_max: isize) -> Option<isize> {
-        if self.intersecting(target) {
-            Some(current_max.max(self.position.1))
-        } else if !self.doomed(target) {
-            self.step().highest_y(target, current_max.max(self.position.1))
-        } else {
-            None
+    // Positions from launch until the probe hits the target or can't any more,
+    // and whether it hit.
+    fn trajectory(&self, target: &Target, physics: &Physics) -> (Vec<(isize, isize)>, bool) {
+        let mut path = vec![self.position];
+        let mut probe = self.step(physics);
+        loop {
+            path.push(probe.position);
+            if probe.intersecting(target) {
+                return (path, true);
+            }
+            if probe.doomed(target, physics) {
+                return (path, false);
+            }
+            probe = probe.step(physics);
         }
     }
+
+    // Iterative, since without gravity a flight can take many steps.
+    fn highest_y(&self, target: &Target, physics: &Physics, current_max: isize) -> Option<isize> {
+        let mut probe = Probe{ ..*self };
+        let mut current_max = current_max;
+        loop {
+            current_max = current_max.max(probe.position.1);
+            if probe.intersecting(target) {
+                return Some(current_max);
+            }
+            if probe.doomed(target, physics) {
+                return None;
+            }
+            probe = probe.step(physics);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn target(x0: isize, x1: isize, y0: isize, y1: isize) -> Target {
+        Target{ x0, x1, y0, y1 }
+    }
+
+    fn parse(s: &str) -> Target {
+        Target::parse(s).unwrap().0
+    }
+
+    #[test]
+    fn example() {
+        let target = parse("target area: x=20..30, y=-10..-5");
+        assert_eq!(solve(&target), Some((45, 112)));
+        assert_eq!(sweep(&target, &Physics::default(), None), Some((45, 112)));
+    }
+
+    fn assert_matches_sweep(x0: isize, x1: isize, y0: isize, y1: isize) {
+        let target = target(x0, x1, y0, y1);
+        assert_eq!(solve(&target), sweep(&target, &Physics::default(), None), "x={}..{} y={}..{}", x0, x1, y0, y1);
+    }
+
+    #[test]
+    fn below_right() {
+        for x0 in [1, 3, 6, 11] {
+            for width in [0, 2, 9] {
+                for y1 in [-1, -4, -12] {
+                    for height in [0, 3, 8] {
+                        assert_matches_sweep(x0, x0 + width, y1 - height, y1);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn below_left() {
+        assert_eq!(solve(&target(-30, -20, -10, -5)), Some((45, 112)));
+        for (x0, x1) in [(-11, -6), (-4, -1), (-9, -9)] {
+            assert_matches_sweep(x0, x1, -12, -4);
+        }
+    }
+
+    #[test]
+    fn straddling_x() {
+        // straight up and down, as well as off to either side
+        assert_eq!(solve(&target(0, 0, -3, -1)), Some((3, 6)));
+        for (x0, x1) in [(-5, 5), (-1, 8), (-8, 0), (0, 3)] {
+            assert_matches_sweep(x0, x1, -9, -2);
+        }
+    }
+
+    #[test]
+    fn above() {
+        // the probe can hit on the way up or on the way down
+        assert_eq!(solve(&target(1, 1, 3, 3)), Some((3, 2)));
+        for (x0, x1) in [(4, 9), (-9, -4), (-3, 3), (7, 7), (16, 20)] {
+            for (y0, y1) in [(1, 1), (2, 6), (10, 12), (5, 20)] {
+                assert_matches_sweep(x0, x1, y0, y1);
+            }
+        }
+    }
+
+    #[test]
+    fn straddling_y() {
+        // 22..27 lies between the triangle numbers 21 and 28, so nothing can
+        // come to rest in it and only a few launches are fast enough
+        for (x0, x1) in [(22, 27), (-27, -22), (4, 5), (7, 9)] {
+            for (y0, y1) in [(-3, 4), (-10, 0), (0, 6)] {
+                assert_matches_sweep(x0, x1, y0, y1);
+            }
+        }
+    }
+
+    #[test]
+    fn unbounded() {
+        assert_eq!(solve(&target(20, 30, -5, 5)), None);
+        assert_eq!(solve(&target(-30, -20, 0, 2)), None);
+        // the launcher itself is inside the target
+        assert_eq!(solve(&target(-1, 1, -1, 1)), None);
+        assert_eq!(sweep(&target(-1, 1, -1, 1), &Physics::default(), None), None);
+    }
+
+    #[test]
+    fn trajectories() {
+        let target = parse("target area: x=20..30, y=-10..-5");
+        let launch = |x_velocity, y_velocity| Probe{ position: (0, 0), x_velocity, y_velocity };
+        let (path, hit) = launch(7, 2).trajectory(&target, &Physics::default());
+        assert!(hit);
+        assert_eq!(path, vec![(0, 0), (7, 2), (13, 3), (18, 3), (22, 2), (25, 0), (27, -3), (28, -7)]);
+        assert_eq!(path_grid(&target, &path), "\
+.............#....#............
+.......#..............#........
+...............................
+S........................#.....
+...............................
+...............................
+...........................#...
+...............................
+....................TTTTTTTTTTT
+....................TTTTTTTTTTT
+....................TTTTTTTT#TT
+....................TTTTTTTTTTT
+....................TTTTTTTTTTT
+....................TTTTTTTTTTT
+");
+        assert!(launch(6, 3).trajectory(&target, &Physics::default()).1);
+        assert!(!launch(17, -4).trajectory(&target, &Physics::default()).1);
+        assert_eq!(launch(17, -4).trajectory(&target, &Physics::default()).0.len(), 3);
+    }
+
+    #[test]
+    fn scatter_plot() {
+        let target = target(3, 4, -2, -1);
+        let velocities: Vec<(isize, isize)> = launches(&target, &Physics::default(), None).unwrap().iter().map(|&(v, _)| v).collect();
+        assert_eq!(scatter(&velocities), "\
+x velocity 2..4, y velocity -2..1
+#..
+#..
+.##
+.##
+");
+        assert_eq!(parse_velocity("7, -2"), Some((7, -2)));
+        assert_eq!(parse_velocity("7"), None);
+    }
+
+    // Whether a launch is ever inside the target within `steps` steps,
+    // without relying on doomed to stop early.
+    fn hits_within(target: &Target, physics: &Physics, velocity: (isize, isize), steps: usize) -> bool {
+        let mut probe = Probe{ position: (0, 0), x_velocity: velocity.0, y_velocity: velocity.1 };
+        (0..steps).any(|_| {
+            probe = probe.step(physics);
+            probe.intersecting(target)
+        })
+    }
+
+    #[test]
+    fn doomed_never_misses_a_hit() {
+        let variants = [
+            Physics::default(),
+            Physics{ gravity: 2, drag: 3, wind: 0, terminal: None },
+            Physics{ gravity: 1, drag: 1, wind: -1, terminal: None },
+            Physics{ gravity: 1, drag: 0, wind: 2, terminal: Some(3) },
+            Physics{ gravity: 0, drag: 1, wind: 0, terminal: None },
+        ];
+        let targets = [target(20, 30, -10, -5), target(-12, -4, 3, 8), target(-3, 6, -6, 2)];
+        for physics in &variants {
+            for target in &targets {
+                let hits = launches(target, physics, Some(25)).unwrap();
+                for vx in -25..=25 {
+                    for vy in -25..=25 {
+                        let found = hits.iter().any(|&(v, _)| v == (vx, vy));
+                        assert_eq!(found, hits_within(target, physics, (vx, vy), 200),
+                            "{:?} x={}..{} y={}..{} launched at {},{}",
+                            physics, target.x0, target.x1, target.y0, target.y1, vx, vy);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wind_and_terminal_velocity() {
+        // overshoots, then the wind brings it back
+        let physics = Physics{ gravity: 1, drag: 1, wind: -2, terminal: None };
+        let probe = Probe{ position: (0, 0), x_velocity: 13, y_velocity: 2 };
+        let (path, hit) = probe.trajectory(&target(20, 30, -10, -5), &physics);
+        assert!(hit);
+        assert_eq!(path, vec![(0, 0), (13, 2), (23, 3), (30, 3), (34, 2), (35, 0), (33, -3), (30, -7)]);
+        let physics = Physics{ gravity: 1, drag: 1, wind: 0, terminal: Some(2) };
+        let probe = Probe{ position: (0, 0), x_velocity: 0, y_velocity: 0 };
+        let path = probe.trajectory(&target(0, 0, -7, -7), &physics).0;
+        assert_eq!(path, vec![(0, 0), (0, 0), (0, -1), (0, -3), (0, -5), (0, -7)]);
+        assert_eq!(parse_physics(&["seventeen", "--gravity", "2", "--terminal", "5", "--window", "40"].map(String::from)),
+            Ok((Physics{ gravity: 2, drag: 1, wind: 0, terminal: Some(5) }, Some(40))));
+        assert!(parse_physics(&["seventeen", "--wind", "1"].map(String::from)).is_err());
+        assert_eq!(parse_physics(&["seventeen", "--trace", "6,9", "--wind", "1"].map(String::from)),
+            Ok((Physics{ gravity: 1, drag: 1, wind: 1, terminal: None }, None)));
+        assert_eq!(parse_physics(&["seventeen", "--wind", "x"].map(String::from)),
+            Err(String::from("--wind expects an integer, got 'x'")));
+        assert_eq!(parse_physics(&["seventeen", "--gravity", "-1"].map(String::from)),
+            Err(String::from("--gravity must not be negative")));
+    }
+
+    #[test]
+    fn parse_variants() {
+        let target = parse("target area: x=20..30, y=-10..-5");
+        let bounds = |t: &Target| (t.x0, t.x1, t.y0, t.y1);
+        for s in [
+            "target area:x = 20 .. 30 ,  y= -10..-5\n",
+            "  target area : y=-10..-5, x=20..30",
+            "x=20..30, y=-10..-5",
+        ] {
+            assert_eq!(Target::parse(s).map(|(t, w)| (bounds(&t), w)), Ok((bounds(&target), vec![])));
+        }
+        let (reversed, warnings) = Target::parse("target area: x=30..20, y=-5..-10").unwrap();
+        assert_eq!(bounds(&reversed), bounds(&target));
+        assert_eq!(warnings, vec![
+            String::from("x range 30..20 is reversed, using 20..30"),
+            String::from("y range -5..-10 is reversed, using -10..-5"),
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |s| Target::parse(s).map(|_| ()).unwrap_err();
+        assert_eq!(error(""), TargetError::Empty);
+        assert_eq!(error("target area:"), TargetError::Empty);
+        assert_eq!(error("target area: x=20..30"), TargetError::MissingAxis('y'));
+        assert_eq!(error("target area: y=1..2, y=3..4"), TargetError::DuplicateAxis('y'));
+        assert_eq!(error("target area: x=1..2, z=3..4"), TargetError::UnknownAxis(String::from("z")));
+        assert_eq!(error("target area: x=20-30, y=1..2"), TargetError::BadRange('x', String::from("20-30")));
+        assert_eq!(error("target area: x=20..30, y=-10..five"), TargetError::NotAnInteger('y', String::from("five")));
+        assert_eq!(error("hello").to_string(), "unknown axis 'hello', expected x or y");
+        assert_eq!(error("x=1.5..2, y=1..2").to_string(), "x bound '1.5' is not an integer");
+    }
+
+    #[test]
+    fn step_runs() {
+        // arc(3, n) for n = 1.. is 3, 5, 6, 6, 5, 3, 0, -4
+        assert_eq!(steps_between(3, 5, 5), vec![(2, 2), (5, 5)]);
+        assert_eq!(steps_between(3, 4, 6), vec![(2, 5)]);
+        assert_eq!(steps_between(3, -3, 1), vec![(7, 7)]);
+        assert_eq!(steps_between(3, 7, 9), vec![]);
+        assert_eq!(steps_between(-2, -9, -5), vec![(2, 3)]);
+    }
+
+    #[test]
+    fn large_target() {
+        let target = target(200_000, 300_000, -400_000, -300_000);
+        let (max_y, _) = solve(&target).unwrap();
+        assert_eq!(max_y, 399_999 * 400_000 / 2);
+    }
 }
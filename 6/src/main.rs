@@ -1,38 +1,430 @@
 use std::io;
+use std::io::Write;
 use std::env;
+use std::process;
+use std::ops::AddAssign;
+use num_bigint::BigUint;
 
 const STARTING_TIMER: usize = 8;
 const REFRESH_TIMER: usize = 6;
 
+type Matrix<T> = Vec<Vec<T>>;
+
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let days = args[1].parse::<usize>().unwrap();
-
-    let mut fish: [i64; 9] = [0; STARTING_TIMER + 1];
+    let days = match args.get(1).map(|s| s.parse::<u64>()) {
+        Some(Ok(days)) => days,
+        _ => {
+            eprintln!("Usage: six DAYS [--starting N] [--refresh N] [--lifespan N] [--step | --mod PRIME | --csv | --jsonl] < input.txt");
+            process::exit(1);
+        },
+    };
+    let (model, modulus) = match parse_options(&args) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
 
     let mut buf = String::new();
     io::stdin().read_line(&mut buf)?;
+    let mut timers = vec![];
     for n in buf.trim().split(",") {
-        fish[n.parse::<usize>().unwrap()] += 1;
+        timers.push(n.parse::<usize>().unwrap());
+    }
+    let mut fish = match model.buckets(&timers) {
+        Ok(fish) => fish,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    let format = if args.iter().any(|a| a == "--csv") {
+        Some(Format::Csv)
+    } else if args.iter().any(|a| a == "--jsonl") {
+        Some(Format::JsonLines)
+    } else {
+        None
+    };
+    if let Some(format) = format {
+        let fish: Vec<BigUint> = fish.iter().map(|&n| BigUint::from(n as u64)).collect();
+        let mut out = io::BufWriter::new(io::stdout().lock());
+        export(&model, fish, days, format, &mut out)?;
+        out.flush()?;
+    } else if args.iter().any(|a| a == "--step") {
+        let mut today = 0;
+        while today < days {
+            model.iterate(&mut fish);
+            today += 1;
+        }
+        println!("Number of fish: {}", fish.iter().sum::<i64>());
+    } else if let Some(m) = modulus {
+        println!("Number of fish mod {}: {}", m, population_mod(&model, &fish, days, m));
+    } else {
+        println!("Number of fish: {}", population(&model, &fish, days));
+    }
+    Ok(())
+}
+
+// Reads --starting, --refresh, --lifespan and --mod, each followed by a
+// positive integer (or zero, for the timers).
+fn parse_options(args: &[String]) -> Result<(Model, Option<u64>), String> {
+    let mut model = Model::default();
+    let mut modulus = None;
+    let mut i = 2;
+    while i < args.len() {
+        let flag = args[i].as_str();
+        if !["--starting", "--refresh", "--lifespan", "--mod"].contains(&flag) {
+            i += 1;
+            continue;
+        }
+        let value = match args.get(i + 1).map(|s| s.parse::<u64>()) {
+            Some(Ok(value)) => value,
+            _ => { return Err(format!("{} expects a non-negative integer", flag)); },
+        };
+        match flag {
+            "--starting" => model.starting = value as usize,
+            "--refresh" => model.refresh = value as usize,
+            "--lifespan" | "--mod" if value == 0 => { return Err(format!("{} must be at least 1", flag)); },
+            "--lifespan" => model.lifespan = Some(value as usize),
+            _ => modulus = Some(value),
+        }
+        i += 2;
+    }
+    Ok((model, modulus))
+}
+
+// Breeding rules. A newborn's timer starts at `starting`, and a fish whose
+// timer has run down to 0 breeds the next day and restarts at `refresh`.
+// With a lifespan, fish die that many days after being born.
+struct Model {
+    starting: usize,
+    refresh: usize,
+    lifespan: Option<usize>,
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Self{
+            starting: STARTING_TIMER,
+            refresh: REFRESH_TIMER,
+            lifespan: None,
+        }
+    }
+}
+
+impl Model {
+    // Fish are counted by timer, or by age when they can die of old age.
+    fn size(&self) -> usize {
+        match self.lifespan {
+            Some(lifespan) => lifespan,
+            None => self.starting.max(self.refresh) + 1,
+        }
+    }
+
+    fn timer_at(&self, age: usize) -> usize {
+        if age <= self.starting {
+            self.starting - age
+        } else {
+            self.refresh - (age - self.starting - 1) % (self.refresh + 1)
+        }
+    }
+
+    // Counts per bucket for fish with the given timers. Counting by age, a
+    // fish is taken to be as young as its timer allows.
+    fn buckets(&self, timers: &[usize]) -> Result<Vec<i64>, String> {
+        let mut fish = vec![0; self.size()];
+        let largest = self.starting.max(self.refresh);
+        for &timer in timers {
+            if timer > largest {
+                return Err(format!("timer {} is more than the largest timer, {}", timer, largest));
+            }
+            let bucket = match self.lifespan {
+                None => timer,
+                Some(_) if timer <= self.starting => self.starting - timer,
+                Some(_) => self.starting + 1 + self.refresh - timer,
+            };
+            match fish.get_mut(bucket) {
+                Some(count) => { *count += 1; },
+                None => {
+                    return Err(format!("timer {} means a fish at least {} days old, but fish live only {} days",
+                        timer, bucket, self.size()));
+                },
+            }
+        }
+        Ok(fish)
+    }
+
+    // The buckets a fish in the given bucket is counted in the next day,
+    // itself included unless it dies.
+    fn next(&self, bucket: usize) -> Vec<usize> {
+        match self.lifespan {
+            None if bucket == 0 => vec![self.refresh, self.starting],
+            None => vec![bucket - 1],
+            Some(lifespan) => {
+                let mut r = vec![];
+                if bucket + 1 < lifespan {
+                    r.push(bucket + 1);
+                }
+                if self.timer_at(bucket) == 0 {
+                    r.push(0);
+                }
+                r
+            },
+        }
+    }
+
+    fn iterate<T: Clone + Default + for<'a> AddAssign<&'a T>>(&self, fish: &mut Vec<T>) {
+        let mut newfish = vec![T::default(); fish.len()];
+        for (bucket, count) in fish.iter().enumerate() {
+            for n in self.next(bucket) {
+                newfish[n] += count;
+            }
+        }
+        *fish = newfish;
+    }
+
+    // Counts per timer, whichever way the buckets are kept.
+    fn by_timer<T: Clone + Default + for<'a> AddAssign<&'a T>>(&self, fish: &[T]) -> Vec<T> {
+        if self.lifespan.is_none() {
+            return fish.to_vec();
+        }
+        let mut r = vec![T::default(); self.starting.max(self.refresh) + 1];
+        for (age, count) in fish.iter().enumerate() {
+            r[self.timer_at(age)] += count;
+        }
+        r
+    }
+
+    // One day as a matrix: entry [i][j] is how many fish in bucket i a fish in
+    // bucket j becomes, the same rules as iterate.
+    fn transition(&self) -> Matrix<u64> {
+        let mut m = vec![vec![0; self.size()]; self.size()];
+        for (i, j) in (0..self.size()).flat_map(|j| self.next(j).into_iter().map(move |i| (i, j))) {
+            m[i][j] += 1;
+        }
+        m
+    }
+}
+
+enum Format {
+    Csv,
+    JsonLines,
+}
+
+// Writes the population and its breakdown by timer for every day from 0 to
+// `days`, one line per day.
+fn export(model: &Model, mut fish: Vec<BigUint>, days: u64, format: Format, out: &mut impl Write) -> io::Result<()> {
+    let timers = model.by_timer(&fish).len();
+    if let Format::Csv = format {
+        let columns: Vec<String> = (0..timers).map(|t| format!("timer_{}", t)).collect();
+        writeln!(out, "day,total,{}", columns.join(","))?;
     }
-    let mut today = 0;
-    while today < days {
-        iterate(&mut fish);
-        today += 1;
+    for day in 0..=days {
+        let counts: Vec<String> = model.by_timer(&fish).iter().map(|n| n.to_string()).collect();
+        let total: BigUint = fish.iter().sum();
+        match format {
+            Format::Csv => writeln!(out, "{},{},{}", day, total, counts.join(","))?,
+            Format::JsonLines => writeln!(out, "{{\"day\":{},\"total\":{},\"timers\":[{}]}}", day, total, counts.join(","))?,
+        }
+        if day < days {
+            model.iterate(&mut fish);
+        }
     }
-    println!("Number of fish: {}", fish.iter().sum::<i64>());
     Ok(())
 }
 
-fn iterate(fish: &mut [i64; STARTING_TIMER+1]) {
-    let mut newfish: [i64; 9] = [0; STARTING_TIMER+1];
-    for n in 0..REFRESH_TIMER {
-        newfish[n] = fish[n+1];
+// Raises m to the nth power by repeated squaring, in O(log n) multiplications.
+fn power<T: Clone>(m: &Matrix<T>, mut n: u64, zero: T, one: T, mul: impl Fn(&Matrix<T>, &Matrix<T>) -> Matrix<T>) -> Matrix<T> {
+    let size = m.len();
+    let mut r = vec![vec![zero; size]; size];
+    for (i, row) in r.iter_mut().enumerate() {
+        row[i] = one.clone();
+    }
+    let mut square = m.clone();
+    while n > 0 {
+        if n % 2 == 1 {
+            r = mul(&r, &square);
+        }
+        n /= 2;
+        if n > 0 {
+            square = mul(&square, &square);
+        }
+    }
+    r
+}
+
+// Exact population after the given number of days. Squaring the matrix costs
+// about size³ multiplications per bit of `days`, against size additions a day
+// going day by day, so long lifespans over short spans go day by day.
+fn population(model: &Model, fish: &[i64], days: u64) -> BigUint {
+    let size = model.size() as u64;
+    let bits = (64 - days.leading_zeros()) as u64;
+    if days <= size.saturating_mul(size).saturating_mul(bits) {
+        let mut fish: Vec<BigUint> = fish.iter().map(|&n| BigUint::from(n as u64)).collect();
+        for _ in 0..days {
+            model.iterate(&mut fish);
+        }
+        return fish.iter().sum();
+    }
+    population_matrix(model, fish, days)
+}
+
+fn population_matrix(model: &Model, fish: &[i64], days: u64) -> BigUint {
+    let m: Matrix<BigUint> = model.transition().iter()
+        .map(|row| row.iter().map(|&x| BigUint::from(x)).collect())
+        .collect();
+    let mul = |a: &Matrix<BigUint>, b: &Matrix<BigUint>| -> Matrix<BigUint> {
+        (0..a.len()).map(|i| (0..a.len()).map(|j| {
+            (0..a.len()).map(|k| &a[i][k] * &b[k][j]).sum()
+        }).collect()).collect()
+    };
+    let m = power(&m, days, BigUint::default(), BigUint::from(1u32), mul);
+    m.iter().flatten().zip(fish.iter().cycle())
+        .map(|(x, &count)| x * count as u64)
+        .sum()
+}
+
+// Population after the given number of days, modulo m. Works for days far
+// beyond what can be counted exactly.
+fn population_mod(model: &Model, fish: &[i64], days: u64, m: u64) -> u64 {
+    let mul = |a: &Matrix<u64>, b: &Matrix<u64>| -> Matrix<u64> {
+        (0..a.len()).map(|i| (0..a.len()).map(|j| {
+            let sum: u128 = (0..a.len()).map(|k| a[i][k] as u128 * b[k][j] as u128 % m as u128).sum();
+            (sum % m as u128) as u64
+        }).collect()).collect()
+    };
+    let t = power(&model.transition(), days, 0, 1 % m, mul);
+    let sum: u128 = t.iter().flatten().zip(fish.iter().cycle())
+        .map(|(&x, &count)| x as u128 * (count as u64 % m) as u128 % m as u128)
+        .sum();
+    (sum % m as u128) as u64
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const EXAMPLE: [usize; 5] = [3, 4, 3, 1, 2];
+
+    #[test]
+    fn matrix_matches_iterate() {
+        let model = Model::default();
+        let example = model.buckets(&EXAMPLE).unwrap();
+        let mut fish = example.clone();
+        for day in 0..=256 {
+            let total = fish.iter().sum::<i64>();
+            assert_eq!(population_matrix(&model, &example, day), BigUint::from(total as u64), "day {}", day);
+            assert_eq!(population(&model, &example, day), BigUint::from(total as u64), "day {}", day);
+            assert_eq!(population_mod(&model, &example, day, 1_000_000_007), total as u64 % 1_000_000_007);
+            model.iterate(&mut fish);
+        }
+        assert_eq!(population(&model, &example, 80), BigUint::from(5934u32));
+        assert_eq!(population(&model, &example, 256), BigUint::from(26984457539u64));
+    }
+
+    fn exported(model: &Model, timers: &[usize], days: u64, format: Format) -> String {
+        let fish = model.buckets(timers).unwrap().iter().map(|&n| BigUint::from(n as u64)).collect();
+        let mut out = vec![];
+        export(model, fish, days, format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn time_series() {
+        let model = Model::default();
+        assert_eq!(exported(&model, &EXAMPLE, 2, Format::Csv), "\
+day,total,timer_0,timer_1,timer_2,timer_3,timer_4,timer_5,timer_6,timer_7,timer_8
+0,5,0,1,1,2,1,0,0,0,0
+1,5,1,1,2,1,0,0,0,0,0
+2,6,1,2,1,0,0,0,1,0,1
+");
+        let lines = exported(&model, &EXAMPLE, 256, Format::JsonLines);
+        assert_eq!(lines.lines().count(), 257);
+        assert_eq!(lines.lines().next(), Some(r#"{"day":0,"total":5,"timers":[0,1,1,2,1,0,0,0,0]}"#));
+        assert!(lines.lines().nth(256).unwrap().starts_with(r#"{"day":256,"total":26984457539,"timers":["#));
+        // counted by age, but still reported by timer
+        let model = Model{ starting: 8, refresh: 6, lifespan: Some(10) };
+        let csv = exported(&model, &EXAMPLE, 12, Format::Csv);
+        assert_eq!(csv.lines().nth(1), Some("0,5,0,1,1,2,1,0,0,0,0"));
+        assert_eq!(csv.lines().last(), Some("12,6,2,1,0,0,0,0,1,1,1"));
     }
-    newfish[REFRESH_TIMER] = fish[REFRESH_TIMER+1] + fish[0];
-    for n in REFRESH_TIMER+1..STARTING_TIMER {
-        newfish[n] = fish[n+1];
+
+    #[test]
+    fn modular() {
+        let model = Model::default();
+        let example = model.buckets(&EXAMPLE).unwrap();
+        let exact = population(&model, &example, 2000);
+        for m in [2, 97, 1_000_000_007, u64::MAX] {
+            assert_eq!(BigUint::from(population_mod(&model, &example, 2000, m)), &exact % m);
+        }
+        assert_eq!(population_mod(&model, &example, 5, 1), 0);
+        // only feasible modulo something
+        assert!(population_mod(&model, &example, 1_000_000_000_000, 998_244_353) < 998_244_353);
+    }
+
+    // Follows each fish individually as (timer, age), with no buckets,
+    // giving the population on each day.
+    fn simulate(model: &Model, timers: &[usize], days: usize) -> Vec<usize> {
+        let mut fish: Vec<(usize, usize)> = timers.iter()
+            .map(|&t| (t, if t <= model.starting { model.starting - t } else { model.starting + 1 + model.refresh - t }))
+            .filter(|&(_, age)| model.lifespan.is_none_or(|l| age < l))
+            .collect();
+        let mut r = vec![fish.len()];
+        for _ in 0..days {
+            let mut born = 0;
+            for f in fish.iter_mut() {
+                if f.0 == 0 {
+                    born += 1;
+                    f.0 = model.refresh;
+                } else {
+                    f.0 -= 1;
+                }
+                f.1 += 1;
+            }
+            fish.retain(|&(_, age)| model.lifespan.is_none_or(|l| age < l));
+            fish.extend((0..born).map(|_| (model.starting, 0)));
+            r.push(fish.len());
+        }
+        r
+    }
+
+    #[test]
+    fn other_species() {
+        for (starting, refresh) in [(8, 6), (2, 2), (3, 5), (1, 0), (5, 1)] {
+            for lifespan in [None, Some(1), Some(4), Some(9), Some(20)] {
+                let model = Model{ starting, refresh, lifespan };
+                // leaving out any fish too old to still be alive
+                let timers: Vec<usize> = EXAMPLE.iter().map(|&t| t.min(starting.max(refresh)))
+                    .filter(|&t| model.buckets(&[t]).is_ok())
+                    .collect();
+                let expected = simulate(&model, &timers, 30);
+                let start = model.buckets(&timers).unwrap();
+                let mut fish = start.clone();
+                for (day, &count) in expected.iter().enumerate() {
+                    assert_eq!(fish.iter().sum::<i64>() as usize, count,
+                        "starting {} refresh {} lifespan {:?} day {}", starting, refresh, lifespan, day);
+                    model.iterate(&mut fish);
+                }
+                assert_eq!(population(&model, &start, 30), BigUint::from(expected[30]));
+            }
+        }
+        assert_eq!(Model::default().buckets(&[9]), Err(String::from("timer 9 is more than the largest timer, 8")));
+        let model = Model{ lifespan: Some(3), ..Model::default() };
+        assert_eq!(model.buckets(&[8, 6]), Ok(vec![1, 0, 1]));
+        assert_eq!(model.buckets(&[3]), Err(String::from("timer 3 means a fish at least 5 days old, but fish live only 3 days")));
+    }
+
+    #[test]
+    fn long_lifespan() {
+        // none of the fish live long enough to die
+        let model = Model{ lifespan: Some(400), ..Model::default() };
+        let example = model.buckets(&EXAMPLE).unwrap();
+        assert_eq!(population(&model, &example, 256), BigUint::from(26984457539u64));
+        let model = Model{ lifespan: Some(1000), ..Model::default() };
+        let example = model.buckets(&EXAMPLE).unwrap();
+        let default = Model::default();
+        assert_eq!(population(&model, &example, 990), population(&default, &default.buckets(&EXAMPLE).unwrap(), 990));
     }
-    newfish[STARTING_TIMER] = fish[0];
-    *fish = newfish;
 }
@@ -1,19 +1,237 @@
 use std::io;
 use std::env;
+use std::fs;
+use std::process;
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
-    let cost_fn = &args[1];
 
     let mut buf = String::new();
     io::stdin().read_line(&mut buf)?;
-    let positions: Vec<i32> = buf.trim().split(",").map(|x| x.parse::<i32>().unwrap()).collect();
+    let positions: Vec<i64> = buf.trim().split(",").map(|x| x.parse::<i64>().unwrap()).collect();
+    let mut weights = String::new();
+    io::stdin().read_line(&mut weights)?;
 
+    let cost_fn = match cost_function(&args, &positions, &weights) {
+        Ok(cost_fn) => cost_fn,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
+    let next = match args.iter().position(|a| a == "--next") {
+        Some(i) => match args.get(i + 1).and_then(|k| k.parse::<usize>().ok()) {
+            Some(k) => Some(k),
+            None => {
+                eprintln!("--next expects a count");
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
+    if args.iter().any(|a| a == "--curve") {
+        println!("position,cost");
+        for (n, c) in curve(&positions, cost_fn.as_ref()) {
+            println!("{},{}", n, c);
+        }
+        return Ok(());
+    }
+    if args.iter().any(|a| a == "--ties") {
+        let (best, best_cost) = optima(&positions, cost_fn.as_ref());
+        let best: Vec<String> = best.iter().map(|n| n.to_string()).collect();
+        println!("Best positions: {}; fuel cost: {}", best.join(", "), best_cost);
+    }
+    if let Some(k) = next {
+        let (_, best_cost) = optima(&positions, cost_fn.as_ref());
+        for (n, c) in next_best(&positions, cost_fn.as_ref(), k) {
+            println!("Next best: position {}; fuel cost: {} (+{})", n, c, c - best_cost);
+        }
+    }
+    if next.is_some() || args.iter().any(|a| a == "--ties") {
+        return Ok(());
+    }
+
+    let (best_position, best_cost) = if args.iter().any(|a| a == "--scan") {
+        scan(&positions, cost_fn.as_ref())
+    } else if args.iter().any(|a| a == "--ternary") {
+        ternary_search(&positions, cost_fn.as_ref())
+    } else {
+        solve(&positions, cost_fn.as_ref())
+    };
+    println!("Best position: {}; fuel cost: {}", best_position, best_cost);
+    Ok(())
+}
+
+// The cost function named by the first argument, or a table read from the
+// file after --table. The weighted cost takes one weight per crab from the
+// second line of input.
+fn cost_function(args: &[String], positions: &[i64], weights: &str) -> Result<Box<dyn FuelCost>, String> {
+    let name = match args.get(1) {
+        Some(name) => name.as_str(),
+        None => { return Err(String::from("Usage: seven COST [--scan | --ternary | --ties | --next K | --curve] < input.txt, with COST a name or --table FILE")); },
+    };
+    match name {
+        "one" | "linear" => Ok(Box::new(Linear)),
+        "two" | "triangular" => Ok(Box::new(Triangular)),
+        "quadratic" => Ok(Box::new(Quadratic)),
+        "weighted" => {
+            let weights = weights.trim().split(",")
+                .map(|w| w.trim().parse::<i64>().ok().filter(|&w| w >= 0)
+                    .ok_or(format!("weight '{}' is not a non-negative integer", w.trim())))
+                .collect::<Result<Vec<i64>, String>>()?;
+            if weights.len() != positions.len() {
+                return Err(format!("{} weights given for {} crabs", weights.len(), positions.len()));
+            }
+            Ok(Box::new(Weighted{ weights }))
+        },
+        "--table" => {
+            let path = args.get(2).ok_or("--table expects a file name")?;
+            let text = fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+            let table = CostTable::parse(&text)?;
+            let (&max, &min) = (positions.iter().max().unwrap(), positions.iter().min().unwrap());
+            if max - min >= table.costs.len() as i64 {
+                return Err(format!("cost table covers distances up to {} but crabs are {} apart",
+                    table.costs.len() - 1, max - min));
+            }
+            Ok(Box::new(table))
+        },
+        _ => Err(format!("unknown cost function '{}', expected one of linear (one), triangular (two), quadratic, weighted or --table FILE", name)),
+    }
+}
+
+// Fuel for a single crab to move a distance.
+trait FuelCost {
+    // `crab` is the crab's index in the input.
+    fn fuel(&self, crab: usize, distance: i64) -> i128;
+
+    // Positions known to include the best one, when there's a shortcut.
+    fn candidates(&self, _positions: &[i64]) -> Option<Vec<i64>> {
+        None
+    }
+
+    // Whether the total cost is convex in the alignment position, which holds
+    // when fuel never falls and rises by at least as much each further step.
+    fn convex(&self) -> bool {
+        true
+    }
+}
+
+struct Linear;
+
+impl FuelCost for Linear {
+    fn fuel(&self, _crab: usize, distance: i64) -> i128 {
+        distance as i128
+    }
+
+    // With an even count everything between the two middle crabs ties, and
+    // this is the last of them.
+    fn candidates(&self, positions: &[i64]) -> Option<Vec<i64>> {
+        let mut sorted = positions.to_vec();
+        let (_, &mut median, _) = sorted.select_nth_unstable(positions.len() / 2);
+        Some(vec![median])
+    }
+}
+
+struct Triangular;
+
+impl FuelCost for Triangular {
+    fn fuel(&self, _crab: usize, distance: i64) -> i128 {
+        tri(distance as i128)
+    }
+
+    // Least within 1 of the mean.
+    fn candidates(&self, positions: &[i64]) -> Option<Vec<i64>> {
+        let mean = mean(positions);
+        Some((mean - 1..=mean + 2).collect())
+    }
+}
+
+struct Quadratic;
+
+impl FuelCost for Quadratic {
+    fn fuel(&self, _crab: usize, distance: i64) -> i128 {
+        let d = distance as i128;
+        d * d
+    }
+
+    // Least at the mean itself.
+    fn candidates(&self, positions: &[i64]) -> Option<Vec<i64>> {
+        let mean = mean(positions);
+        Some(vec![mean, mean + 1])
+    }
+}
+
+// Linear cost scaled by each crab's own weight.
+struct Weighted {
+    weights: Vec<i64>,
+}
+
+impl FuelCost for Weighted {
+    fn fuel(&self, crab: usize, distance: i64) -> i128 {
+        self.weights[crab] as i128 * distance as i128
+    }
+
+    // The weighted median: moving right stops paying off at the first crab
+    // with more than half the weight at or left of it.
+    fn candidates(&self, positions: &[i64]) -> Option<Vec<i64>> {
+        let mut crabs: Vec<(i64, i64)> = positions.iter().copied().zip(self.weights.iter().copied()).collect();
+        crabs.sort_unstable();
+        let total: i128 = self.weights.iter().map(|&w| w as i128).sum();
+        let mut left = 0;
+        for (position, weight) in crabs {
+            left += weight as i128;
+            if 2 * left > total {
+                return Some(vec![position]);
+            }
+        }
+        // every weight is zero, so every position ties
+        positions.iter().max().map(|&max| vec![max])
+    }
+}
+
+// Fuel for each distance from 0 up, as read from a file.
+struct CostTable {
+    costs: Vec<i128>,
+}
+
+impl CostTable {
+    // Integers separated by commas or whitespace.
+    fn parse(text: &str) -> Result<Self, String> {
+        let costs = text.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| s.parse::<i128>().map_err(|_| format!("cost table entry '{}' is not an integer", s)))
+            .collect::<Result<Vec<i128>, String>>()?;
+        if costs.is_empty() {
+            return Err(String::from("cost table is empty"));
+        }
+        Ok(Self{ costs })
+    }
+}
+
+impl FuelCost for CostTable {
+    fn fuel(&self, _crab: usize, distance: i64) -> i128 {
+        self.costs[distance as usize]
+    }
+
+    fn convex(&self) -> bool {
+        let steps: Vec<i128> = self.costs.windows(2).map(|w| w[1] - w[0]).collect();
+        steps.iter().all(|&s| s >= 0) && steps.windows(2).all(|w| w[0] <= w[1])
+    }
+}
+
+fn mean(positions: &[i64]) -> i64 {
+    let sum: i128 = positions.iter().map(|&x| x as i128).sum();
+    sum.div_euclid(positions.len() as i128) as i64
+}
+
+// Tries every position between the outermost crabs, keeping the last of any
+// that tie.
+fn scan(positions: &[i64], cost_fn: &dyn FuelCost) -> (i64, i128) {
     let (&max, &min) = (positions.iter().max().unwrap(), positions.iter().min().unwrap());
-    let mut best_cost: Option<i32> = None;
-    let mut best_position: Option<i32> = None;
+    let mut best_cost: Option<i128> = None;
+    let mut best_position: Option<i64> = None;
     for n in min..=max {
-        let cost = cost(&positions, n, cost_fn);
+        let cost = cost(positions, n, cost_fn);
         if let Some(x) = best_cost {
             if x < cost {
                 continue;
@@ -22,18 +240,222 @@ fn main() -> io::Result<()> {
         best_cost = Some(cost);
         best_position = Some(n);
     }
-    println!("Best position: {}; fuel cost: {}", best_position.unwrap(), best_cost.unwrap());
-    Ok(())
+    (best_position.unwrap(), best_cost.unwrap())
 }
 
-fn cost(positions: &Vec<i32>, align_to: i32, cost_fn: &str) -> i32 {
-    if cost_fn == "one" {
-        positions.iter().map(|x| i32::abs(x-align_to)).sum()
-    } else {
-        positions.iter().map(|x| tri(i32::abs(x-align_to))).sum()
+// Costs only the cost function's candidate positions if it has any, and
+// otherwise searches convex costs and scans the rest. Ties resolve as in scan.
+fn solve(positions: &[i64], cost_fn: &dyn FuelCost) -> (i64, i128) {
+    let candidates = match cost_fn.candidates(positions) {
+        Some(candidates) => candidates,
+        None if cost_fn.convex() => { return ternary_search(positions, cost_fn); },
+        None => { return scan(positions, cost_fn); },
+    };
+    candidates.into_iter()
+        .map(|n| (n, cost(positions, n, cost_fn)))
+        .fold(None, |best: Option<(i64, i128)>, (n, c)| match best {
+            Some((_, x)) if x < c => best,
+            _ => Some((n, c)),
+        })
+        .unwrap()
+}
+
+// For any cost that is convex in the alignment position: binary search on
+// the sign of the cost's slope between the outermost crabs, in O(log range)
+// cost evaluations. Finds the last of any tied positions.
+fn ternary_search(positions: &[i64], cost_fn: &dyn FuelCost) -> (i64, i128) {
+    let (mut lo, mut hi) = (*positions.iter().min().unwrap(), *positions.iter().max().unwrap());
+    // the answer is the first n in lo..=hi with cost(n + 1) > cost(n), or hi
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if cost(positions, mid + 1, cost_fn) > cost(positions, mid, cost_fn) {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
     }
+    (lo, cost(positions, lo, cost_fn))
+}
+
+// Cost of every alignment between the outermost crabs.
+fn curve(positions: &[i64], cost_fn: &dyn FuelCost) -> Vec<(i64, i128)> {
+    let (&max, &min) = (positions.iter().max().unwrap(), positions.iter().min().unwrap());
+    (min..=max).map(|n| (n, cost(positions, n, cost_fn))).collect()
 }
 
-fn tri(n: i32) -> i32 {
+// Every position tied for least fuel, and that fuel.
+fn optima(positions: &[i64], cost_fn: &dyn FuelCost) -> (Vec<i64>, i128) {
+    if !cost_fn.convex() {
+        let curve = curve(positions, cost_fn);
+        let best = curve.iter().map(|&(_, c)| c).min().unwrap();
+        return (curve.iter().filter(|&&(_, c)| c == best).map(|&(n, _)| n).collect(), best);
+    }
+    // convex, so the ties are a run ending at the one solve finds
+    let (last, best) = solve(positions, cost_fn);
+    let min = *positions.iter().min().unwrap();
+    let mut first = last;
+    while first > min && cost(positions, first - 1, cost_fn) == best {
+        first -= 1;
+    }
+    ((first..=last).collect(), best)
+}
+
+// The k cheapest alignments after the optimal ones, cheapest and then
+// leftmost first.
+fn next_best(positions: &[i64], cost_fn: &dyn FuelCost, k: usize) -> Vec<(i64, i128)> {
+    let (optima, best) = optima(positions, cost_fn);
+    if !cost_fn.convex() {
+        let mut curve = curve(positions, cost_fn);
+        curve.retain(|&(_, c)| c > best);
+        curve.sort_by_key(|&(n, c)| (c, n));
+        curve.truncate(k);
+        return curve;
+    }
+    // convex, so the cost only rises moving out from the optima either way
+    let (&max, &min) = (positions.iter().max().unwrap(), positions.iter().min().unwrap());
+    let (mut left, mut right) = (optima[0] - 1, optima[optima.len() - 1] + 1);
+    let mut next = Vec::new();
+    while next.len() < k {
+        let l = (left >= min).then(|| cost(positions, left, cost_fn));
+        let r = (right <= max).then(|| cost(positions, right, cost_fn));
+        match (l, r) {
+            (Some(l), r) if r.is_none_or(|r| l <= r) => {
+                next.push((left, l));
+                left -= 1;
+            },
+            (_, Some(r)) => {
+                next.push((right, r));
+                right += 1;
+            },
+            _ => break,
+        }
+    }
+    next
+}
+
+// Summed in i128, since a million crabs spread over a range of 10^9 can
+// need more than 10^23 fuel.
+fn cost(positions: &[i64], align_to: i64, cost_fn: &dyn FuelCost) -> i128 {
+    positions.iter().enumerate().map(|(crab, x)| cost_fn.fuel(crab, i64::abs(x-align_to))).sum()
+}
+
+fn tri(n: i128) -> i128 {
     n*(n+1)/2
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const EXAMPLE: [i64; 10] = [16, 1, 2, 0, 4, 2, 7, 1, 2, 14];
+
+    fn named(name: &str, positions: &[i64], weights: &str) -> Result<Box<dyn FuelCost>, String> {
+        cost_function(&[String::from("seven"), String::from(name)], positions, weights)
+    }
+
+    #[test]
+    fn example() {
+        for solver in [scan, solve, ternary_search] {
+            assert_eq!(solver(&EXAMPLE, &Linear), (2, 37));
+            assert_eq!(solver(&EXAMPLE, &Triangular), (5, 168));
+        }
+    }
+
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self, range: u64) -> i64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % range) as i64
+        }
+    }
+
+    #[test]
+    fn solvers_agree() {
+        let mut rng = Rng(0x2545f4914f6cdd1d);
+        for _ in 0..300 {
+            let count = 1 + rng.next(12) as usize;
+            let spread = 1 + rng.next(40) as u64;
+            let positions: Vec<i64> = (0..count).map(|_| rng.next(spread) - 10).collect();
+            let weights: Vec<i64> = (0..count).map(|_| rng.next(5)).collect();
+            let costs: [Box<dyn FuelCost>; 4] = [Box::new(Linear), Box::new(Triangular), Box::new(Quadratic), Box::new(Weighted{ weights })];
+            for cost_fn in &costs {
+                let expected = scan(&positions, cost_fn.as_ref());
+                assert_eq!(solve(&positions, cost_fn.as_ref()), expected, "{:?}", positions);
+                assert_eq!(ternary_search(&positions, cost_fn.as_ref()), expected, "{:?}", positions);
+            }
+        }
+    }
+
+    #[test]
+    fn large_input() {
+        // a scan would cost every one of 2 * 10^9 positions
+        let positions: Vec<i64> = (0..200_000).map(|i| (i * 7919) % 2_000_000_000 - 1_000_000_000).collect();
+        let (n, c) = solve(&positions, &Triangular);
+        assert_eq!(ternary_search(&positions, &Triangular), (n, c));
+        assert!(cost(&positions, n - 1, &Triangular) >= c && cost(&positions, n + 1, &Triangular) > c);
+    }
+
+    // Hides a cost's convexity, so callers fall back to the full curve.
+    struct Opaque<'a>(&'a dyn FuelCost);
+
+    impl FuelCost for Opaque<'_> {
+        fn fuel(&self, crab: usize, distance: i64) -> i128 {
+            self.0.fuel(crab, distance)
+        }
+
+        fn convex(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn ties_and_next_best() {
+        assert_eq!(optima(&[1, 3], &Linear), (vec![1, 2, 3], 2));
+        assert_eq!(optima(&[0, 3], &Triangular), (vec![1, 2], 4));
+        assert_eq!(optima(&EXAMPLE, &Triangular), (vec![5], 168));
+        assert_eq!(next_best(&EXAMPLE, &Triangular, 3), vec![(4, 170), (6, 176), (3, 183)]);
+        assert_eq!(next_best(&[1, 3], &Linear, 2), vec![]);
+        assert_eq!(curve(&[1, 3], &Triangular), vec![(1, 3), (2, 2), (3, 3)]);
+
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..200 {
+            let count = 1 + rng.next(8) as usize;
+            let spread = 1 + rng.next(30) as u64;
+            let positions: Vec<i64> = (0..count).map(|_| rng.next(spread)).collect();
+            let costs: [Box<dyn FuelCost>; 3] = [Box::new(Linear), Box::new(Triangular), Box::new(Quadratic)];
+            for cost_fn in &costs {
+                let opaque = Opaque(cost_fn.as_ref());
+                assert_eq!(optima(&positions, cost_fn.as_ref()), optima(&positions, &opaque), "{:?}", positions);
+                let k = rng.next(spread + 2) as usize;
+                assert_eq!(next_best(&positions, cost_fn.as_ref(), k), next_best(&positions, &opaque, k), "{:?}", positions);
+            }
+        }
+    }
+
+    #[test]
+    fn cost_tables() {
+        let table = CostTable::parse("0, 1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 66, 78, 91, 105, 120, 136").unwrap();
+        assert!(table.convex());
+        assert_eq!(solve(&EXAMPLE, &table), (5, 168));
+        // cheaper to go far than middling, so not convex
+        let table = CostTable::parse("0 5 9\n9 9 9 9 9 9 9 9\n9 9 9 9 9 9 1").unwrap();
+        assert!(!table.convex());
+        assert_eq!(solve(&EXAMPLE, &table), scan(&EXAMPLE, &table));
+        assert_eq!(solve(&EXAMPLE, &table), (2, 55));
+        assert_eq!(CostTable::parse("1,x").map(|_| ()), Err(String::from("cost table entry 'x' is not an integer")));
+    }
+
+    #[test]
+    fn named_costs() {
+        assert_eq!(solve(&EXAMPLE, named("linear", &EXAMPLE, "").unwrap().as_ref()), (2, 37));
+        assert_eq!(solve(&EXAMPLE, named("quadratic", &EXAMPLE, "").unwrap().as_ref()), (5, 291));
+        let weights = "1,1,1,1,1,1,1,1,1,100";
+        assert_eq!(solve(&EXAMPLE, named("weighted", &EXAMPLE, weights).unwrap().as_ref()), (14, 95));
+        assert_eq!(named("weighted", &EXAMPLE, "1,2").map(|_| ()), Err(String::from("2 weights given for 10 crabs")));
+        assert_eq!(named("weighted", &EXAMPLE, "1,-2").map(|_| ()), Err(String::from("weight '-2' is not a non-negative integer")));
+        assert!(named("cubic", &EXAMPLE, "").err().unwrap().starts_with("unknown cost function 'cubic'"));
+    }
+}
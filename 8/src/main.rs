@@ -1,8 +1,64 @@
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io;
+use std::process;
+use std::time::SystemTime;
 use itertools::Itertools;
 
 const UNIQUE_LENGTHS: [usize; 4] = [2,3,4,7];
+const SEVEN_SEGMENT: &str = "0: abcefg
+1: cf
+2: acdeg
+3: acdfg
+4: bcdf
+5: abdfg
+6: abdefg
+7: acf
+8: abcdefg
+9: abcdfg";
+
 fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let display = match args.iter().position(|a| a == "--display") {
+        Some(i) => {
+            let table = args.get(i + 1)
+                .ok_or(String::from("--display expects a file name"))
+                .and_then(|path| fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e)))
+                .and_then(|text| Display::parse(&text));
+            match table {
+                Ok(display) => Some(display),
+                Err(e) => {
+                    eprintln!("Invalid display table: {}", e);
+                    process::exit(1);
+                },
+            }
+        },
+        None => None,
+    };
+
+    if let Some(i) = args.iter().position(|a| a == "--generate") {
+        let number = match args.get(i + 1).and_then(|n| n.parse::<u32>().ok()).filter(|&n| n < 10000) {
+            Some(number) => number,
+            None => {
+                eprintln!("--generate expects a number below 10000");
+                process::exit(1);
+            },
+        };
+        let seed = match args.iter().position(|a| a == "--seed") {
+            Some(i) => match args.get(i + 1).and_then(|s| s.parse::<u64>().ok()) {
+                Some(seed) => seed,
+                None => {
+                    eprintln!("--seed expects a number");
+                    process::exit(1);
+                },
+            },
+            None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos() as u64,
+        };
+        println!("{}", generate(number, &mut Rng::new(seed)));
+        return Ok(());
+    }
+
     let mut lines: Vec<String> = vec![];
     loop {
         let mut buf = String::new();
@@ -14,18 +70,58 @@ fn main() -> io::Result<()> {
         }
     }
 
+    // any other display just has its outputs spelled out
+    if let Some(display) = display {
+        for (i, line) in lines.iter().enumerate() {
+            match decode_line(line, &display) {
+                Ok(output) => println!("{}", output),
+                Err(e) => eprintln!("line {}: {}", i + 1, e),
+            }
+        }
+        return Ok(());
+    }
+
+    if args.iter().any(|a| a == "--render") {
+        for (i, line) in lines.iter().enumerate() {
+            match decode_line(line, &Display::seven_segment()) {
+                Ok(output) => print!("{}", render(&output)),
+                Err(e) => eprintln!("line {}: {}", i + 1, e),
+            }
+        }
+        return Ok(());
+    }
+
     let count: usize = lines.iter().map(|x| count_unique_lengths(x)).sum();
     println!("Number of 1/4/7/8s: {}", count);
 
-    let count2: i32 = lines.iter().map(|x| line_to_num(x)).sum();
+    // bad lines are reported and left out of the sum
+    let mut count2: i64 = 0;
+    let mut invalid = 0;
+    for (i, line) in lines.iter().enumerate() {
+        match line_to_num(line) {
+            Ok(n) => { count2 += n as i64 },
+            Err(errors) => {
+                invalid += 1;
+                for e in errors {
+                    eprintln!("line {}: {}", i + 1, e);
+                }
+            },
+        }
+    }
     println!("Sum of all values: {}", count2);
+    if invalid > 0 {
+        eprintln!("Skipped {} invalid lines of {}", invalid, lines.len());
+    }
 
     Ok(())
 }
 
 fn count_unique_lengths(s: &str) -> usize {
-    let ss: Vec<&str> = s.split("|").collect();
-    let r = ss[1]
+    let output = match s.split_once("|") {
+        Some((_, output)) => output,
+        None => { return 0; },
+    };
+    let r = output
         .split(" ")
         .map(|x| x.len())
         .filter(|x| UNIQUE_LENGTHS.contains(x))
@@ -33,66 +129,376 @@ fn count_unique_lengths(s: &str) -> usize {
     r
 }
 
-fn string_to_digit(s: &str, code_map: &[String; 10]) -> char {
+fn string_to_digit(s: &str, code_map: &[String; 10]) -> Result<char, DecodeError> {
     let o_s: String = s.chars().sorted().collect();
-    char::from_digit(code_map.iter().position(|x| *x == o_s).unwrap() as u32, 10).unwrap()
+    match code_map.iter().position(|x| *x == o_s) {
+        Some(digit) => Ok(char::from_digit(digit as u32, 10).unwrap()),
+        None => Err(DecodeError::UnmatchedOutput(String::from(s))),
+    }
+}
+
+// A note's value, or everything found wrong with it.
+fn line_to_num(s: &str) -> Result<i32, Vec<DecodeError>> {
+    let (input, output) = s.split_once("|").ok_or(vec![DecodeError::MissingOutput])?;
+    let (code_map, mut errors) = diagnose(&input.split_whitespace().collect::<Vec<&str>>());
+    let outputs: Vec<&str> = output.split_whitespace().collect();
+    if outputs.is_empty() {
+        errors.push(DecodeError::MissingOutput);
+    }
+    let mut digits = String::new();
+    if let Some(code_map) = code_map {
+        for x in outputs {
+            match string_to_digit(x, &code_map) {
+                Ok(digit) => digits.push(digit),
+                Err(e) => errors.push(e),
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    digits.parse::<i32>().map_err(|_| vec![DecodeError::Overflow(digits)])
+}
+
+// Decodes as much of a note's patterns as it can, finding duplicated
+// patterns, any single pattern that isn't a digit and digits with no pattern.
+fn diagnose(patterns: &[&str]) -> (Option<[String; 10]>, Vec<DecodeError>) {
+    let mut errors = Vec::new();
+    let mut unique: Vec<&str> = Vec::new();
+    for &pattern in patterns {
+        let sorted: String = pattern.chars().sorted().collect();
+        if !unique.iter().any(|p| p.chars().sorted().collect::<String>() == sorted) {
+            unique.push(pattern);
+        } else if !errors.contains(&DecodeError::Duplicate(String::from(pattern))) {
+            errors.push(DecodeError::Duplicate(String::from(pattern)));
+        }
+    }
+    let code_map = match process_input(&unique) {
+        Ok(code_map) => code_map,
+        Err(e) => {
+            // a typo in one pattern is the likeliest reason, so see whether
+            // the note decodes without any one of them
+            let culprit = (0..unique.len()).find_map(|i| {
+                let mut rest = unique.clone();
+                let bad = rest.remove(i);
+                process_input(&rest).ok().map(|code_map| (bad, code_map))
+            });
+            match culprit {
+                Some((bad, code_map)) => {
+                    errors.push(DecodeError::BadPattern(String::from(bad)));
+                    code_map
+                },
+                None => {
+                    errors.push(e);
+                    return (None, errors);
+                },
+            }
+        },
+    };
+    let missing: Vec<char> = (0..10).filter(|&d| code_map[d].is_empty())
+        .map(|d| char::from_digit(d as u32, 10).unwrap())
+        .collect();
+    if !missing.is_empty() {
+        errors.push(DecodeError::MissingDigits(missing));
+    }
+    (Some(code_map), errors)
 }
 
-fn line_to_num(s: &str) -> i32 {
+// The symbols shown by the output half of a note.
+fn decode_line(s: &str, display: &Display) -> Result<String, DecodeError> {
     let mut split = s.split("|");
-    let (input, output) = split.next_tuple().unwrap();
-    let code_map = process_input(
-        &input.split(" ")
-        .map(|x| x.trim())
-        .collect::<Vec<&str>>()
-    );
-    output.trim().split(" ").map(|x| string_to_digit(x, &code_map))
-    .collect::<String>().parse::<i32>().unwrap()
+    let (input, output) = split.next_tuple().ok_or(DecodeError::MissingOutput)?;
+    let patterns: Vec<&str> = input.split_whitespace().collect();
+    let symbols = display.decode(&patterns)?;
+    let mut masks = Vec::new();
+    for pattern in &patterns {
+        masks.push(display.mask(pattern)?);
+    }
+    output.split_whitespace().map(|x| {
+        let mask = display.mask(x)?;
+        masks.iter().position(|&m| m == mask)
+            .map(|i| symbols[i])
+            .ok_or(DecodeError::UnmatchedOutput(String::from(x)))
+    }).collect()
 }
 
-fn process_input<'a>(input: &Vec<&'a str>) -> [String; 10] {
+// The patterns for each digit of the standard display, sorted, or empty for
+// any digit not among the input.
+fn process_input(input: &[&str]) -> Result<[String; 10], DecodeError> {
     let mut r: [String; 10] = Default::default();
-    // order input strings first.
-    let o_input: Vec<String> = input.iter().map(|&s| s.chars().sorted().collect::<String>()).collect();
-    for item in &o_input {
-        let index = match item.chars().count() {
-            2 => 1,
-            3 => 7,
-            4 => 4,
-            7 => 8,
-            _ => 0,
-        };
-        r[index] = item.clone();
+    let symbols = Display::seven_segment().decode(input)?;
+    for (pattern, symbol) in input.iter().zip(symbols) {
+        r[symbol.to_digit(10).unwrap() as usize] = pattern.chars().sorted().collect();
+    }
+    Ok(r)
+}
+
+// A note for a four-digit number on a seven-segment display with randomly
+// crossed wires, with the patterns and the wires within each in random order.
+fn generate(number: u32, rng: &mut Rng) -> String {
+    let display = Display::seven_segment();
+    let mut wires: Vec<char> = display.segments.clone();
+    rng.shuffle(&mut wires);
+    let mut scrambled = |symbol: char| -> String {
+        let &(_, mask) = display.symbols.iter().find(|&&(s, _)| s == symbol).unwrap();
+        let mut pattern: Vec<char> = (0..wires.len()).filter(|&i| mask & 1 << i != 0).map(|i| wires[i]).collect();
+        rng.shuffle(&mut pattern);
+        pattern.into_iter().collect()
+    };
+    let mut digits: Vec<char> = "0123456789".chars().collect();
+    let mut patterns: Vec<String> = Vec::new();
+    for &digit in &digits {
+        patterns.push(scrambled(digit));
     }
-    for item in &o_input {
-        if item.len() != 6 {
-            continue;
+    let output: Vec<String> = format!("{:04}", number).chars().map(&mut scrambled).collect();
+    rng.shuffle(&mut digits);
+    let patterns: Vec<&str> = digits.iter().map(|&d| patterns[d.to_digit(10).unwrap() as usize].as_str()).collect();
+    format!("{} | {}", patterns.join(" "), output.join(" "))
+}
+
+// Draws digits as seven-segment art, three lines tall:
+//  _     _  _     _  _  _  _  _
+// | |  | _| _||_||_ |_   ||_||_|
+// |_|  ||_  _|  | _||_|  ||_| _|
+fn render(digits: &str) -> String {
+    let display = Display::seven_segment();
+    // the segment, if any, at each place of a digit's 3x3 cell
+    let cell = [
+        [None, Some(('a', '_')), None],
+        [Some(('b', '|')), Some(('d', '_')), Some(('c', '|'))],
+        [Some(('e', '|')), Some(('g', '_')), Some(('f', '|'))],
+    ];
+    let masks: Vec<u32> = digits.chars()
+        .map(|d| display.symbols.iter().find(|&&(s, _)| s == d).map_or(0, |&(_, m)| m))
+        .collect();
+    let mut r = String::new();
+    for row in &cell {
+        for mask in &masks {
+            for place in row {
+                r.push(match place {
+                    Some((segment, c)) if mask & display.mask(&segment.to_string()).unwrap() != 0 => *c,
+                    _ => ' ',
+                });
+            }
         }
-        if string_difference(&item, &r[1]) == 5 {
-            r[6] = item.clone();
-        } else if string_difference(&item, &r[4]) == 3 {
-            r[0] = item.clone();
-        } else {
-            r[9] = item.clone();
+        r.push('\n');
+    }
+    r
+}
+
+// xorshift64, which is plenty for scrambling notes.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self, range: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % range as u64) as usize
+    }
+
+    fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.next(i + 1));
         }
     }
-    for item in &o_input {
-        if item.len() != 5 {
-            continue;
+}
+
+// A display's segments, named by letter, and which of them light up for
+// each symbol. Segments are bits of a mask in alphabetical order.
+struct Display {
+    segments: Vec<char>,
+    symbols: Vec<(char, u32)>,
+}
+
+impl Display {
+    fn seven_segment() -> Self {
+        Self::parse(SEVEN_SEGMENT).unwrap()
+    }
+
+    // One "symbol: segments" line per symbol.
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut definitions = Vec::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let (symbol, segments) = line.split_once(":").ok_or(format!("expected 'symbol: segments' but got '{}'", line))?;
+            let symbol = symbol.trim();
+            if symbol.chars().count() != 1 {
+                return Err(format!("symbol '{}' is not a single character", symbol));
+            }
+            let segments = segments.trim();
+            if let Some(c) = segments.chars().find(|c| !c.is_ascii_alphabetic()) {
+                return Err(format!("segment '{}' of symbol {} is not a letter", c, symbol));
+            }
+            definitions.push((symbol.chars().next().unwrap(), segments));
+        }
+        let segments: Vec<char> = definitions.iter().flat_map(|(_, s)| s.chars()).sorted().dedup().collect();
+        if segments.is_empty() {
+            return Err(String::from("no symbol lights any segments"));
         }
-        if string_difference(&r[1], &item) == 0 {
-            r[3] = item.clone();
-        } else if string_difference(&r[4], &item) == 1 {
-            r[5] = item.clone();
-        } else {
-            r[2] = item.clone();
+        if segments.len() > 32 {
+            return Err(format!("{} segments is more than the 32 supported", segments.len()));
         }
+        let mut display = Self{ segments, symbols: Vec::new() };
+        for (symbol, segments) in definitions {
+            let mask = display.mask(segments).unwrap();
+            if let Some(&(other, _)) = display.symbols.iter().find(|&&(s, m)| s == symbol || m == mask) {
+                return Err(if other == symbol {
+                    format!("symbol {} is defined twice", symbol)
+                } else {
+                    format!("symbols {} and {} light the same segments", other, symbol)
+                });
+            }
+            display.symbols.push((symbol, mask));
+        }
+        if display.symbols.is_empty() {
+            return Err(String::from("no symbols defined"));
+        }
+        Ok(display)
+    }
+
+    fn mask(&self, pattern: &str) -> Result<u32, DecodeError> {
+        pattern.chars().try_fold(0, |mask, c| match self.segments.iter().position(|&s| s == c) {
+            Some(i) => Ok(mask | 1 << i),
+            None => Err(DecodeError::UnknownWire(c)),
+        })
+    }
+
+    // Finds every wiring, from each wire to the segment it actually drives,
+    // under which the patterns are all symbols of this display, and so the
+    // symbol each pattern shows. Fails unless they all agree.
+    fn decode(&self, patterns: &[&str]) -> Result<Vec<char>, DecodeError> {
+        let mut masks = Vec::new();
+        for pattern in patterns {
+            masks.push(self.mask(pattern)?);
+        }
+        let n = self.segments.len();
+        let all = u32::MAX >> (32 - n);
+        let mut candidates = vec![all; n];
+
+        // a wire lit in a pattern drives a segment lit in some symbol of the
+        // same size, and an unlit one a segment unlit in one
+        for &mask in &masks {
+            let fits: Vec<u32> = self.symbols.iter().map(|&(_, m)| m).filter(|m| m.count_ones() == mask.count_ones()).collect();
+            let lit = fits.iter().fold(0, |a, m| a | m);
+            let unlit = fits.iter().fold(0, |a, m| a | (!m & all));
+            for (wire, c) in candidates.iter_mut().enumerate() {
+                *c &= if mask & 1 << wire != 0 { lit } else { unlit };
+            }
+        }
+        // with every symbol shown once, a wire is lit in patterns of just the
+        // sizes that its segment's symbols have
+        if masks.len() == self.symbols.len() && masks.iter().unique().count() == masks.len() {
+            let sizes = |masks: &mut dyn Iterator<Item = u32>, bit: usize| -> Vec<u32> {
+                masks.filter(|m| m & 1 << bit != 0).map(|m| m.count_ones()).sorted().collect()
+            };
+            for (wire, c) in candidates.iter_mut().enumerate() {
+                let wire_sizes = sizes(&mut masks.iter().copied(), wire);
+                for segment in 0..n {
+                    if sizes(&mut self.symbols.iter().map(|&(_, m)| m), segment) != wire_sizes {
+                        *c &= !(1 << segment);
+                    }
+                }
+            }
+        }
+
+        // wires that are never lit don't change what anything reads as
+        let lit = masks.iter().fold(0, |a, m| a | m);
+        let mut readings = Vec::new();
+        self.search(&masks, &candidates, lit, &mut vec![0; n], &mut readings);
+        match readings.len() {
+            0 => Err(DecodeError::NoWiring),
+            1 => Ok(readings.pop().unwrap()),
+            _ => Err(DecodeError::Ambiguous(readings.iter().map(|r| r.iter().collect()).collect())),
+        }
+    }
+
+    // Backtracks over the lit wires, most constrained first, and stops once
+    // two different readings turn up.
+    fn search(&self, masks: &[u32], candidates: &[u32], unassigned: u32, wiring: &mut Vec<u32>, readings: &mut Vec<Vec<char>>) {
+        if readings.len() > 1 {
+            return;
+        }
+        let used = wiring.iter().fold(0, |a, m| a | m);
+        let wire = (0..wiring.len())
+            .filter(|&w| unassigned & 1 << w != 0)
+            .min_by_key(|&w| (candidates[w] & !used).count_ones());
+        let wire = match wire {
+            Some(wire) => wire,
+            None => {
+                let reading: Option<Vec<char>> = masks.iter().map(|&mask| {
+                    let shown = self.image(mask, wiring);
+                    self.symbols.iter().find(|&&(_, m)| m == shown).map(|&(s, _)| s)
+                }).collect();
+                if let Some(reading) = reading {
+                    if !readings.contains(&reading) {
+                        readings.push(reading);
+                    }
+                }
+                return;
+            },
+        };
+        let options = candidates[wire] & !used;
+        for segment in 0..wiring.len() {
+            if options & 1 << segment == 0 {
+                continue;
+            }
+            wiring[wire] = 1 << segment;
+            if self.consistent(masks, wiring) {
+                self.search(masks, candidates, unassigned & !(1 << wire), wiring, readings);
+            }
+        }
+        wiring[wire] = 0;
+    }
+
+    // Whether each pattern could still be some symbol, given the wires
+    // assigned so far.
+    fn consistent(&self, masks: &[u32], wiring: &[u32]) -> bool {
+        let assigned = (0..wiring.len()).filter(|&w| wiring[w] != 0).fold(0, |a, w| a | 1 << w);
+        masks.iter().all(|&mask| {
+            let on = self.image(mask & assigned, wiring);
+            let off = self.image(!mask & assigned, wiring);
+            self.symbols.iter().any(|&(_, m)| m.count_ones() == mask.count_ones() && m & on == on && m & off == 0)
+        })
+    }
+
+    // The segments lit by a pattern's wires.
+    fn image(&self, mask: u32, wiring: &[u32]) -> u32 {
+        (0..wiring.len()).filter(|&w| mask & 1 << w != 0).fold(0, |a, w| a | wiring[w])
     }
-    r
 }
 
-fn string_difference(a: &str, b: &str) -> usize {
-    a.chars().filter(|c| !b.contains(*c)).count()
+#[derive(Debug, PartialEq)]
+enum DecodeError {
+    MissingOutput,
+    UnknownWire(char),
+    NoWiring,
+    Ambiguous(Vec<String>),
+    UnmatchedOutput(String),
+    Duplicate(String),
+    BadPattern(String),
+    MissingDigits(Vec<char>),
+    Overflow(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::MissingOutput => write!(f, "no '|' before the output patterns"),
+            DecodeError::UnknownWire(c) => write!(f, "wire '{}' is not a segment of the display", c),
+            DecodeError::NoWiring => write!(f, "no wiring shows every pattern as a symbol"),
+            DecodeError::Ambiguous(readings) => write!(f, "the patterns could read as any of {}", readings.join(", ")),
+            DecodeError::UnmatchedOutput(s) => write!(f, "output '{}' is not one of the patterns", s),
+            DecodeError::Duplicate(s) => write!(f, "pattern '{}' appears more than once", s),
+            DecodeError::BadPattern(s) => write!(f, "pattern '{}' is not any digit", s),
+            DecodeError::MissingDigits(digits) => write!(f, "no pattern for {}", digits.iter().join(", ")),
+            DecodeError::Overflow(digits) => write!(f, "output {} is too large a value", digits),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -107,7 +513,7 @@ mod tests {
             "abe",
             "abcdefg",
         ];
-        let r = process_input(&input);
+        let r = process_input(&input).unwrap();
         assert_eq!(r[1], "ab");
         assert_eq!(r[4], "abcd");
         assert_eq!(r[7], "abe");
@@ -128,7 +534,7 @@ mod tests {
             "abcdefg", //8
             "abcdfg", //9 abcdfg
         ];
-        let r = process_input(&input);
+        let r = process_input(&input).unwrap();
         assert_eq!(r[2], "acdeg");
         assert_eq!(r[5], "abdfg");
         assert_eq!(r[3], "acdfg");
@@ -148,7 +554,7 @@ mod tests {
             "abcdefg", //8
             "abcdfg", //9 abcdfg
         ];
-        let r = process_input(&input);
+        let r = process_input(&input).unwrap();
         assert_eq!(r[0], "abcefg");
         assert_eq!(r[6], "abdefg");
         assert_eq!(r[9], "abcdfg");
@@ -168,7 +574,106 @@ mod tests {
             "gabcdef", //8
             "gabcdf", //9 abcdfg
         ];
-        let r = process_input(&input);
+        let r = process_input(&input).unwrap();
         assert_eq!(r[0], "abcefg");
     }
-}
\ No newline at end of file
+
+    const SIXTEEN_SEGMENT: &str = "0: abcdefghmp
+1: cdm
+2: abcefgij
+3: abcdefj
+4: cdhij
+5: abdefhij
+6: abdefghij
+7: abcd
+8: abcdefghij
+9: abcdefhij
+A: abcdghij
+B: abcdefjlo
+C: abefgh
+D: abcdeflo
+E: abefghi
+F: abghi";
+
+    // Rewires the patterns of a display's symbols, in some order.
+    fn scramble(display: &Display, order: &str, wires: &str) -> Vec<String> {
+        let wires: Vec<char> = wires.chars().collect();
+        order.chars().map(|symbol| {
+            let &(_, mask) = display.symbols.iter().find(|&&(s, _)| s == symbol).unwrap();
+            (0..wires.len()).filter(|&i| mask & 1 << i != 0).map(|i| wires[i]).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn test_example_line() {
+        let line = "acedgfb cdfbe gcdfa fbcad dab cefabd cdfgeb eafb cagedb ab | cdfeb fcadb cdfeb cdbaf";
+        assert_eq!(line_to_num(line), Ok(5353));
+        assert_eq!(line_to_num("ab abc abcd acd | ab"), Err(vec![DecodeError::NoWiring]));
+        assert_eq!(line_to_num(&line.replace(" |", "")), Err(vec![DecodeError::MissingOutput]));
+    }
+
+    #[test]
+    fn test_diagnose() {
+        let line = "acedgfb cdfbe gcdfa fbcad dab cefabd cdfgeb eafb cagedb ab | cdfeb fcadb cdfeb cdbaf";
+        // 5 is cdfbe, 3 fbcad and 7 dab
+        assert_eq!(line_to_num(&line.replace("cdfbe gcdfa", "cdfbx gcdfa")), Err(vec![
+            DecodeError::BadPattern(String::from("cdfbx")),
+            DecodeError::MissingDigits(vec!['5']),
+            DecodeError::UnmatchedOutput(String::from("cdfeb")),
+            DecodeError::UnmatchedOutput(String::from("cdfeb")),
+        ]));
+        assert_eq!(line_to_num(&line.replace(" dab ", " bcfad ")), Err(vec![
+            DecodeError::Duplicate(String::from("bcfad")),
+            DecodeError::MissingDigits(vec!['7']),
+        ]));
+        assert_eq!(line_to_num(&line.replace("| cdfeb", "| cdfeg")), Err(vec![
+            DecodeError::UnmatchedOutput(String::from("cdfeg")),
+        ]));
+        assert_eq!(line_to_num(&line.replace(" | cdfeb fcadb cdfeb cdbaf", " |")), Err(vec![DecodeError::MissingOutput]));
+        let long = line.replace("cdfeb fcadb cdfeb cdbaf", &["cdfeb"; 12].join(" "));
+        assert_eq!(line_to_num(&long), Err(vec![DecodeError::Overflow(String::from("555555555555"))]));
+        assert_eq!(decode_line("ab dab | xy", &Display::seven_segment()), Err(DecodeError::UnknownWire('x')));
+    }
+
+    #[test]
+    fn test_sixteen_segment() {
+        let display = Display::parse(SIXTEEN_SEGMENT).unwrap();
+        let order = "7E3A0C91F6D25B84";
+        let patterns = scramble(&display, order, "pbhmcoegfjdail");
+        let patterns: Vec<&str> = patterns.iter().map(|p| p.as_str()).collect();
+        assert_eq!(display.decode(&patterns), Ok(order.chars().collect()));
+        let line = format!("{} | {} {} {}", patterns.join(" "), patterns[4], patterns[3], patterns[13]);
+        assert_eq!(decode_line(&line, &display), Ok(String::from("0AB")));
+    }
+
+    #[test]
+    fn test_generated_notes() {
+        let mut rng = Rng::new(0x2545f4914f6cdd1d);
+        for _ in 0..500 {
+            let number = rng.next(10000) as u32;
+            let line = generate(number, &mut rng);
+            assert_eq!(line_to_num(&line), Ok(number as i32), "{}", line);
+            assert_eq!(count_unique_lengths(&line), format!("{:04}", number).chars().filter(|c| "1478".contains(*c)).count());
+            let decoded = decode_line(&line, &Display::seven_segment()).unwrap();
+            assert_eq!(render(&decoded), render(&format!("{:04}", number)));
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render("0123456789"), concat!(
+            " _     _  _     _  _  _  _  _ \n",
+            "| |  | _| _||_||_ |_   ||_||_|\n",
+            "|_|  ||_  _|  | _||_|  ||_| _|\n",
+        ));
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        let display = Display::parse("x: ab\ny: bc").unwrap();
+        assert_eq!(display.decode(&["ab", "bc"]), Err(DecodeError::Ambiguous(vec![String::from("xy"), String::from("yx")])));
+        assert_eq!(Display::parse("x: ab\ny: ba").map(|_| ()), Err(String::from("symbols x and y light the same segments")));
+        assert_eq!(Display::parse("x: a1").map(|_| ()), Err(String::from("segment '1' of symbol x is not a letter")));
+        assert_eq!(Display::parse("x:").map(|_| ()), Err(String::from("no symbol lights any segments")));
+    }
+}
@@ -1,6 +1,16 @@
+use std::env;
 use std::io;
+use std::process;
 
 fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let terrain = match parse_terrain(&args) {
+        Ok(terrain) => terrain,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        },
+    };
     let mut lines: Vec<String> = vec![];
     loop {
         let mut buf = String::new();
@@ -19,116 +29,249 @@ fn main() -> io::Result<()> {
         }
         locs.push(loc);
     }
-    println!("Sum of low point risks: {}", low_sum(&locs));
-
-    let mut world = World::init(&locs);
+    let mut world = World::init(&locs, terrain);
     world.visit_all_basins();
-    let mut basins = world.basins;
+    if args.iter().any(|a| a == "--letters") {
+        print!("{}", world.render(false));
+        return Ok(());
+    }
+    if args.iter().any(|a| a == "--colour") {
+        print!("{}", world.render(true));
+        return Ok(());
+    }
+    if args.iter().any(|a| a == "--basins") {
+        for basin in &world.basins {
+            let (rn, cn) = world.location(basin.low_point);
+            println!("Basin {}: low point ({}, {}) at height {}; size {}",
+                basin.id, rn, cn, world.nodes[basin.low_point].weight, basin.size());
+        }
+        return Ok(());
+    }
+
+    println!("Sum of low point risks: {}", low_sum(&world));
+    let basins = largest_basins(&world, 3);
+    let product: usize = basins.iter().product();
+    if basins.len() < 3 {
+        println!("Fewer than 3 basins ({}); product of their sizes: {}", basins.len(), product);
+    } else {
+        println!("Product of largest 3 basins: {}", product);
+    }
+    Ok(())
+}
+
+const LETTERS: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
+
+// --neighbours 4|8|hex, --wall HEIGHT and --equal-lows.
+fn parse_terrain(args: &[String]) -> Result<Terrain, String> {
+    let mut terrain = Terrain::default();
+    let value = |flag: &str| args.iter().position(|a| a == flag).map(|i| args.get(i + 1).map(|v| v.as_str()));
+    match value("--neighbours") {
+        Some(Some("4")) => { terrain.neighbourhood = Neighbourhood::Four },
+        Some(Some("8")) => { terrain.neighbourhood = Neighbourhood::Eight },
+        Some(Some("hex")) => { terrain.neighbourhood = Neighbourhood::Hex },
+        Some(_) => { return Err(String::from("--neighbours expects 4, 8 or hex")); },
+        None => {},
+    }
+    match value("--wall") {
+        Some(Some(wall)) => {
+            terrain.wall = wall.parse::<i32>().map_err(|_| format!("--wall expects a height but got '{}'", wall))?;
+        },
+        Some(None) => { return Err(String::from("--wall expects a height")); },
+        None => {},
+    }
+    terrain.equal_lows = args.iter().any(|a| a == "--equal-lows");
+    Ok(terrain)
+}
+
+// The sizes of the n largest basins, or of them all if there are fewer.
+fn largest_basins(world: &World, n: usize) -> Vec<usize> {
+    let mut basins: Vec<usize> = world.basins.iter().map(|b| b.size()).collect();
     basins.sort();
     basins.reverse();
-    println!("Product of largest 3 basins: {}", basins[0]*basins[1]*basins[2]);
-    Ok(())
+    basins.truncate(n);
+    basins
 }
 
-fn low_sum(locs: &Vec<Vec<i32>>) -> i32 {
-    let mut r = 0;
-    let (rows, cols) = (locs.len(), locs[0].len());
-    for rn in 0..rows {
-        for cn in 0..cols {
-            let l = locs[rn][cn];
-            if (rn == 0 || l < locs[rn-1][cn])
-                && (rn == rows - 1 || l < locs[rn+1][cn])
-                && (cn == 0 || l < locs[rn][cn-1])
-                && (cn == cols - 1 || l < locs[rn][cn+1]) {
-                r += l + 1;
-            }
+fn low_sum(world: &World) -> i32 {
+    world.low_points().iter().map(|&i| world.nodes[i].weight + 1).sum()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Neighbourhood {
+    Four,
+    Eight,
+    // rows of hexagons, with each odd row shifted half a cell right
+    Hex,
+}
+
+// What counts as next to, walled off from and lower than what.
+#[derive(Clone, Copy, Debug)]
+struct Terrain {
+    neighbourhood: Neighbourhood,
+    // cells at least this high belong to no basin
+    wall: i32,
+    // whether a cell no higher than any neighbour is a low point, rather
+    // than only one lower than them all
+    equal_lows: bool,
+}
+
+impl Default for Terrain {
+    fn default() -> Self {
+        Self{
+            neighbourhood: Neighbourhood::Four,
+            wall: 9,
+            equal_lows: false,
         }
     }
-    r
 }
 
 struct World {
     size: (usize, usize),
-    nodes: Vec<Vec<Node>>,
-    basins: Vec<i32>,
+    terrain: Terrain,
+    // row by row
+    nodes: Vec<Node>,
+    basins: Vec<Basin>,
 }
 
 impl World {
-    fn init(map: &Vec<Vec<i32>>) -> Self {
+    fn init(map: &[Vec<i32>], terrain: Terrain) -> Self {
         let rows = map.len();
         let cols = map[0].len();
-        let mut nodes: Vec<Vec<Node>> = vec![];
-        for rn in 0..rows {
-            let mut row = vec![];
-            for cn in 0..cols {
+        let mut nodes: Vec<Node> = Vec::with_capacity(rows * cols);
+        for heights in map {
+            for &weight in heights {
                 let node = Node{
-                    weight: map[rn][cn],
-                    visited: false,
+                    weight,
+                    basin: None,
                 };
-                row.push(node);
+                nodes.push(node);
             }
-            nodes.push(row);
         }
         World{
             size: (rows, cols),
+            terrain,
             nodes,
             basins: vec![],
         }
     }
 
-    // visitor algorithm for basins
-    // store all nodes and their neighbours
-    // start at first node
-    // each neighbour, if it's not already visited and not 9, add to basin; mark as visited regardless
-    // repeat for each neighbour
-    // no need to check further for end
-    // move to next node
-    // if visited/9, move to next node; if not, start new basin.
-    // after moving through all nodes, check list of basins, find biggest 3, multiply together.
-    fn visit_all_basins(self: &mut Self) {
-        for rn in 0..self.size.0 {
-            for cn in 0..self.size.1 {
-                let basin = self.visit_basin((rn, cn));
-                self.basins.push(basin);
+    // Each cell in reading order that isn't a wall or already in a basin
+    // starts a new one, filled by visit_basin. Basins are numbered in the
+    // order they're found, and a basin's low point is its lowest cell, the
+    // first in reading order if there's a tie.
+    fn visit_all_basins(&mut self) {
+        for i in 0..self.nodes.len() {
+            let node = &self.nodes[i];
+            if node.basin.is_some() || node.weight >= self.terrain.wall {
+                continue;
             }
+            let id = self.basins.len();
+            let cells = self.visit_basin(i, id as u32);
+            let low_point = *cells.iter().min_by_key(|&&c| (self.nodes[c].weight, c)).unwrap();
+            self.basins.push(Basin{
+                id,
+                low_point,
+                cells,
+            });
         }
     }
 
-    fn visit_basin(self: &mut Self, location: (usize, usize)) -> i32 {
-        let (rn, cn) = location;
-        let mut node = &mut self.nodes[rn][cn];
-        if node.visited || node.weight == 9 {
-            return 0;
-        }
-        node.visited = true;
-        let mut r = 1;
-        for n_location in self.neighbours(rn, cn) {
-            r += self.visit_basin(n_location);
+    // Fills a basin from a stack of cells still to look around. Cells are
+    // labelled as they're pushed, so each is pushed once and the stack never
+    // outgrows the basin.
+    fn visit_basin(&mut self, start: usize, id: u32) -> Vec<usize> {
+        let mut cells = vec![];
+        let mut stack = vec![start];
+        self.nodes[start].basin = Some(id);
+        while let Some(i) = stack.pop() {
+            cells.push(i);
+            for n in self.neighbours(i) {
+                let node = &mut self.nodes[n];
+                if node.basin.is_none() && node.weight < self.terrain.wall {
+                    node.basin = Some(id);
+                    stack.push(n);
+                }
+            }
         }
-        r
+        cells
     }
 
-    fn neighbours(self: &Self, rn: usize, cn: usize) -> Vec<(usize, usize)> {
-        let mut r = vec![];
-        if rn > 0 {
-            r.push((rn-1,cn));
-        }
-        if cn > 0 {
-            r.push((rn,cn-1));
-        }
-        if rn+1 < self.size.0 {
-            r.push((rn+1,cn));
-        }
-        if cn+1 < self.size.1 {
-            r.push((rn,cn+1));
+    fn low_points(&self) -> Vec<usize> {
+        (0..self.nodes.len()).filter(|&i| {
+            let l = self.nodes[i].weight;
+            self.neighbours(i).all(|n| {
+                let h = self.nodes[n].weight;
+                l < h || (self.terrain.equal_lows && l == h)
+            })
+        }).collect()
+    }
+
+    // Row and column of a cell's index in the flattened grid.
+    fn location(&self, i: usize) -> (usize, usize) {
+        (i / self.size.1, i % self.size.1)
+    }
+
+    // The heightmap with each basin's cells in its own colour, or else its
+    // own letter, and its low point highlighted: bold and reversed, or in
+    // upper case. Walls are left as they are.
+    fn render(&self, colour: bool) -> String {
+        let mut r = String::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            match node.basin {
+                Some(id) => {
+                    let id = id as usize;
+                    let low = self.basins[id].low_point == i;
+                    if colour {
+                        let style = if low { "1;7;" } else { "" };
+                        r.push_str(&format!("\x1b[{}3{}m{}\x1b[0m", style, 1 + id % 6, node.weight));
+                    } else {
+                        let letter = LETTERS[id % LETTERS.len()] as char;
+                        r.push(if low { letter.to_ascii_uppercase() } else { letter });
+                    }
+                },
+                None => r.push_str(&node.weight.to_string()),
+            }
+            if (i + 1) % self.size.1 == 0 {
+                r.push('\n');
+            }
         }
         r
     }
+
+    fn neighbours(&self, i: usize) -> impl Iterator<Item = usize> {
+        let (rn, cn) = self.location(i);
+        let (rn, cn) = (rn as isize, cn as isize);
+        let offsets: &[(isize, isize)] = match self.terrain.neighbourhood {
+            Neighbourhood::Four => &[(-1, 0), (0, -1), (1, 0), (0, 1)],
+            Neighbourhood::Eight => &[(-1, -1), (-1, 0), (-1, 1), (0, -1), (0, 1), (1, -1), (1, 0), (1, 1)],
+            Neighbourhood::Hex if rn % 2 == 0 => &[(-1, -1), (-1, 0), (0, -1), (0, 1), (1, -1), (1, 0)],
+            Neighbourhood::Hex => &[(-1, 0), (-1, 1), (0, -1), (0, 1), (1, 0), (1, 1)],
+        };
+        let (rows, cols) = (self.size.0 as isize, self.size.1 as isize);
+        offsets.iter().filter_map(move |&(dr, dc)| {
+            let (r, c) = (rn + dr, cn + dc);
+            (r >= 0 && r < rows && c >= 0 && c < cols).then_some((r * cols + c) as usize)
+        })
+    }
 }
 
 struct Node {
     weight: i32,
-    visited: bool,
+    // u32 keeps nodes small on big maps
+    basin: Option<u32>,
+}
+
+// Cells are indices into the world's flattened grid.
+struct Basin {
+    id: usize,
+    low_point: usize,
+    cells: Vec<usize>,
+}
+
+impl Basin {
+    fn size(&self) -> usize {
+        self.cells.len()
+    }
 }
 
 #[cfg(test)]
@@ -139,7 +282,7 @@ mod tests {
     fn test_inner() {
         let mut locations = vec![vec![2; 10]; 5];
         locations[3][2] = 1;
-        assert_eq!(low_sum(&locations), 2);
+        assert_eq!(low_sum(&World::init(&locations, Terrain::default())), 2);
     }
 
     #[test]
@@ -149,7 +292,116 @@ mod tests {
         locations[4][2] = 1;
         locations[2][0] = 1;
         locations[2][9] = 1;
-        assert_eq!(low_sum(&locations), 8);
+        assert_eq!(low_sum(&World::init(&locations, Terrain::default())), 8);
+    }
+
+    const EXAMPLE: &str = "2199943210
+3987894921
+9856789892
+8767896789
+9899965678";
+
+    fn example() -> World {
+        let locs: Vec<Vec<i32>> = EXAMPLE.lines()
+            .map(|l| l.chars().map(|c| c.to_digit(10).unwrap() as i32).collect())
+            .collect();
+        let mut world = World::init(&locs, Terrain::default());
+        world.visit_all_basins();
+        world
+    }
+
+    #[test]
+    fn test_basins() {
+        let world = example();
+        let basins: Vec<(usize, (usize, usize), usize)> = world.basins.iter()
+            .map(|b| (b.id, world.location(b.low_point), b.size()))
+            .collect();
+        assert_eq!(basins, vec![(0, (0, 1), 3), (1, (0, 9), 9), (2, (2, 2), 14), (3, (4, 6), 9)]);
+        for basin in &world.basins {
+            assert!(basin.cells.iter().all(|&c| world.nodes[c].basin == Some(basin.id as u32)));
+        }
+        assert_eq!(world.render(false), "\
+aA999bbbbB
+a9ccc9b9bb
+9cCccc9d9b
+ccccc9ddd9
+9c999dDddd
+");
+        assert!(world.render(true).starts_with("\x1b[31m2\x1b[0m\x1b[1;7;31m1\x1b[0m99"));
+    }
+
+    #[test]
+    fn test_large_basin() {
+        // one winding basin four million cells long, far deeper than any
+        // stack would take recursively
+        let (rows, cols) = (2000, 2000);
+        let mut locs = vec![vec![5; cols]; rows];
+        for (rn, row) in locs.iter_mut().enumerate() {
+            if rn % 2 == 1 {
+                row.fill(9);
+                row[if rn % 4 == 1 { cols - 1 } else { 0 }] = 5;
+            }
+        }
+        locs[0][0] = 0;
+        let mut world = World::init(&locs, Terrain::default());
+        world.visit_all_basins();
+        assert_eq!(world.basins.len(), 1);
+        assert_eq!(world.basins[0].size(), rows * cols / 2 + rows / 2);
+        assert_eq!(world.basins[0].low_point, 0);
+    }
+
+    fn world(map: &str, terrain: Terrain) -> World {
+        let locs: Vec<Vec<i32>> = map.lines()
+            .map(|l| l.chars().map(|c| c.to_digit(10).unwrap() as i32).collect())
+            .collect();
+        let mut world = World::init(&locs, terrain);
+        world.visit_all_basins();
+        world
+    }
+
+    #[test]
+    fn test_neighbourhoods() {
+        let four = Terrain::default();
+        let eight = Terrain{ neighbourhood: Neighbourhood::Eight, ..four };
+        let hex = Terrain{ neighbourhood: Neighbourhood::Hex, ..four };
+        assert_eq!(world("19\n91", four).basins.len(), 2);
+        assert_eq!(world("19\n91", eight).basins.len(), 1);
+        // the odd row is shifted right, so it touches only the cell above
+        // and the one above and to the right
+        assert_eq!(world("19\n91", hex).basins.len(), 2);
+        assert_eq!(world("91\n19", hex).basins.len(), 1);
+        assert_eq!(world("91\n19", four).basins.len(), 2);
+
+        let mut corner = world("022\n212\n222", four);
+        assert_eq!(low_sum(&corner), 3);
+        corner.terrain = eight;
+        assert_eq!(low_sum(&corner), 1);
+        assert_eq!(world("000\n000\n000", hex).neighbours(4).count(), 6);
+        assert_eq!(world("000\n000\n000", eight).neighbours(0).collect::<Vec<usize>>(), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_fewer_than_three_basins() {
+        assert_eq!(largest_basins(&example(), 3), vec![14, 9, 9]);
+        let eight = Terrain{ neighbourhood: Neighbourhood::Eight, ..Terrain::default() };
+        assert_eq!(largest_basins(&world(EXAMPLE, eight), 3), vec![35]);
+        assert_eq!(largest_basins(&world(EXAMPLE, Terrain{ wall: 3, ..Terrain::default() }), 3), vec![6, 2]);
+    }
+
+    #[test]
+    fn test_walls_and_equal_lows() {
+        let terrain = Terrain{ wall: 5, ..Terrain::default() };
+        assert_eq!(world("151", terrain).basins.len(), 2);
+        assert_eq!(world("151", Terrain{ wall: 6, ..terrain }).basins.len(), 1);
+        assert_eq!(world("171", terrain).basins.len(), 2);
+
+        assert_eq!(low_sum(&world("22\n33", Terrain::default())), 0);
+        assert_eq!(low_sum(&world("22\n33", Terrain{ equal_lows: true, ..Terrain::default() })), 6);
+
+        let args: Vec<String> = ["nine", "--neighbours", "hex", "--wall", "7", "--equal-lows"].iter().map(|a| a.to_string()).collect();
+        let terrain = parse_terrain(&args).unwrap();
+        assert_eq!((terrain.neighbourhood, terrain.wall, terrain.equal_lows), (Neighbourhood::Hex, 7, true));
+        assert!(parse_terrain(&[String::from("nine"), String::from("--neighbours"), String::from("6")]).is_err());
     }
 
     #[test]
@@ -159,6 +411,6 @@ mod tests {
         locations[4][0] = 1;
         locations[0][9] = 1;
         locations[4][9] = 1;
-        assert_eq!(low_sum(&locations), 8);
+        assert_eq!(low_sum(&World::init(&locations, Terrain::default())), 8);
     }
 }